@@ -0,0 +1,36 @@
+//! Fuzz the signature pattern parser
+//!
+//! Any string must either parse into a consistent pattern or fail with an
+//! error that points inside the string; it must never panic.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use systemdetection::pattern::Pattern;
+
+fuzz_target!(|text: &str| {
+    let pattern = match Pattern::parse(text) {
+        Ok(pattern) => pattern,
+        Err(e) => {
+            assert!(e.position <= text.len(), "{} in {:?}", e, text);
+            let _ = e.to_string();
+            return;
+        }
+    };
+
+    assert!(pattern.len() > 0);
+    assert!(pattern.patch_point() <= pattern.len());
+    for capture in pattern.captures() {
+        assert!(capture.len > 0);
+        assert!(capture.offset + capture.len <= pattern.len());
+    }
+    if let Some((offset, _)) = pattern.anchor() {
+        assert!(offset < pattern.len());
+    }
+
+    // Matching never reads past the data, however short
+    let data = vec![0u8; pattern.len()];
+    for len in 0..=data.len() {
+        let _ = pattern.matches(&data[..len]);
+    }
+});
@@ -0,0 +1,123 @@
+//! Offline Dunia.dll scanner and patcher
+//!
+//! Usage:
+//!   dunia-tool scan <Dunia.dll>
+//!   dunia-tool patch <Dunia.dll> <output>
+//!   dunia-tool sig <Dunia.dll> <rva>
+
+use std::process::ExitCode;
+use systemdetection::offline::DuniaFile;
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    let result = match args.as_slice() {
+        ["scan", input] => scan(input),
+        ["patch", input, output] => patch(input, output),
+        ["sig", input, rva] => signature(input, rva),
+        _ => {
+            eprintln!("usage: dunia-tool scan <Dunia.dll>");
+            eprintln!("       dunia-tool patch <Dunia.dll> <output>");
+            eprintln!("       dunia-tool sig <Dunia.dll> <rva>");
+            return ExitCode::from(2);
+        }
+    };
+
+    match result {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Report where every signature resolves; returns false if any failed
+fn scan(input: &str) -> Result<bool, String> {
+    let file = std::fs::read(input).map_err(|e| format!("{}: {}", input, e))?;
+    let dunia = DuniaFile::parse(&file).map_err(|e| format!("{}: {}", input, e))?;
+    let base = dunia.image_base();
+
+    println!("{} (image base 0x{:08X})", input, base);
+    print_build(&dunia);
+
+    let mut all_found = true;
+    for report in dunia.scan() {
+        let kind = if report.hook { "hook" } else { "patch" };
+        match report.result {
+            Ok(address) => println!(
+                "  {:<5} {:<20} 0x{:08X} (rva 0x{:08X}){}",
+                kind,
+                report.name,
+                address,
+                address - base,
+                if report.from_table { " [table]" } else { "" }
+            ),
+            Err(e) => {
+                all_found = false;
+                println!("  {:<5} {:<20} {}", kind, report.name, e);
+            }
+        }
+    }
+
+    Ok(all_found)
+}
+
+/// Write a statically patched copy of `input`; returns false if any enabled patch failed
+fn patch(input: &str, output: &str) -> Result<bool, String> {
+    let mut file = std::fs::read(input).map_err(|e| format!("{}: {}", input, e))?;
+    let dunia = DuniaFile::parse(&file).map_err(|e| format!("{}: {}", input, e))?;
+    print_build(&dunia);
+
+    let mut all_applied = true;
+    for report in dunia.patch(&mut file) {
+        if !report.enabled {
+            println!("  {:<20} disabled", report.name);
+            continue;
+        }
+
+        match report.result {
+            Ok(addresses) => {
+                let addresses: Vec<String> = addresses
+                    .iter()
+                    .map(|address| format!("0x{:08X}", address))
+                    .collect();
+                println!("  {:<20} {}", report.name, addresses.join(", "));
+            }
+            Err(e) => {
+                all_applied = false;
+                println!("  {:<20} {}", report.name, e);
+            }
+        }
+    }
+
+    std::fs::write(output, &file).map_err(|e| format!("{}: {}", output, e))?;
+    println!("Wrote {}", output);
+
+    Ok(all_applied)
+}
+
+/// Print the shortest unique signature for a hexadecimal RVA
+fn signature(input: &str, rva: &str) -> Result<bool, String> {
+    let rva = usize::from_str_radix(rva.trim_start_matches("0x"), 16)
+        .map_err(|e| format!("invalid rva {}: {}", rva, e))?;
+
+    let file = std::fs::read(input).map_err(|e| format!("{}: {}", input, e))?;
+    let dunia = DuniaFile::parse(&file).map_err(|e| format!("{}: {}", input, e))?;
+    let generated = dunia
+        .signature(rva)
+        .map_err(|e| format!("rva 0x{:08X}: {}", rva, e))?;
+
+    println!("{}", generated.definition("Name"));
+
+    Ok(true)
+}
+
+fn print_build(dunia: &DuniaFile) {
+    match dunia.build_name() {
+        Some(name) => println!("Build: {} ({})", name, dunia.build_id()),
+        None => println!("Build: unknown ({})", dunia.build_id()),
+    }
+}
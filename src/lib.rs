@@ -1,15 +1,20 @@
 //! Far Cry 2 systemdetection.dll drop-in replacement
 
+#![cfg_attr(test, feature(test))]
+
 mod gear;
 mod patches;
 
 pub use gear::GearHardware;
 pub use gear::GearScore;
+pub use patches::offline;
+#[cfg(fuzzing)]
+pub use patches::pattern;
 
 use std::ffi::c_void;
 use std::sync::OnceLock;
 use windows::Win32::Foundation::HMODULE;
-use windows::Win32::System::SystemServices::DLL_PROCESS_ATTACH;
+use windows::Win32::System::SystemServices::{DLL_PROCESS_ATTACH, DLL_PROCESS_DETACH};
 
 /// DLL entry point
 ///
@@ -18,9 +23,9 @@ use windows::Win32::System::SystemServices::DLL_PROCESS_ATTACH;
 #[unsafe(no_mangle)]
 #[allow(non_snake_case)]
 pub unsafe extern "system" fn DllMain(
-    _hinst_dll: HMODULE,
+    hinst_dll: HMODULE,
     fdw_reason: u32,
-    _lpv_reserved: *mut c_void,
+    lpv_reserved: *mut c_void,
 ) -> i32 {
     if fdw_reason == DLL_PROCESS_ATTACH {
         #[cfg(debug_assertions)]
@@ -35,7 +40,12 @@ pub unsafe extern "system" fn DllMain(
         }
 
         // Apply Dunia.dll patches
-        patches::apply_patches();
+        patches::apply_patches(hinst_dll);
+    } else if fdw_reason == DLL_PROCESS_DETACH && lpv_reserved.is_null() {
+        // Undo Dunia.dll patches when unloaded by FreeLibrary. On process exit
+        // (non-null reserved) the other threads have been terminated and
+        // Dunia.dll may already be detached, so the patches are left in place.
+        patches::revert_patches();
     }
     1 // TRUE
 }
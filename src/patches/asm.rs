@@ -0,0 +1,616 @@
+//! A small x86 assembler for patch bytes
+//!
+//! Patches are written as assembly instead of hand-encoded bytes. Assembly is
+//! done by a `const fn`, so in a const context (including an inline
+//! `const { }` block) a typo or an out-of-range operand is a compile error:
+//!
+//! ```ignore
+//! let bytes = const { asm::assemble("jmp short +0x0E") }; // EB 0E
+//! ```
+//!
+//! Only what patches need is supported:
+//!
+//! - `nop`, `int3`, `ret`, `ret imm16`
+//! - `push r32`/`imm`, `pop r32`, `inc r32`, `dec r32`
+//! - `mov`, `xor` and `test` between registers of the same size, and
+//!   `mov r8, imm8` / `mov r32, imm32`
+//! - `jmp`, `jmp short`, `call`, `jcc` and `jcc short`
+//!
+//! Branch operands are displacements from the end of the instruction, as
+//! encoded (`+0x0E`, `-5`), since patch bytes are assembled without knowing
+//! where they will be written. Instructions are separated by `;` or newlines.
+
+/// Most bytes one piece of assembly may produce
+pub const CAPACITY: usize = 32;
+
+const REG32: [&str; 8] = ["eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi"];
+const REG8: [&str; 8] = ["al", "cl", "dl", "bl", "ah", "ch", "dh", "bh"];
+
+/// Condition suffixes of `jcc` and their condition codes
+const CONDITIONS: [(&str, u8); 30] = [
+    ("o", 0x0),
+    ("no", 0x1),
+    ("b", 0x2),
+    ("c", 0x2),
+    ("nae", 0x2),
+    ("ae", 0x3),
+    ("nb", 0x3),
+    ("nc", 0x3),
+    ("e", 0x4),
+    ("z", 0x4),
+    ("ne", 0x5),
+    ("nz", 0x5),
+    ("be", 0x6),
+    ("na", 0x6),
+    ("a", 0x7),
+    ("nbe", 0x7),
+    ("s", 0x8),
+    ("ns", 0x9),
+    ("p", 0xA),
+    ("pe", 0xA),
+    ("np", 0xB),
+    ("po", 0xB),
+    ("l", 0xC),
+    ("nge", 0xC),
+    ("ge", 0xD),
+    ("nl", 0xD),
+    ("le", 0xE),
+    ("ng", 0xE),
+    ("g", 0xF),
+    ("nle", 0xF),
+];
+
+/// Why assembly text was rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AsmErrorKind {
+    /// The text contains no instructions
+    Empty,
+    /// A mnemonic that is not supported
+    UnknownMnemonic,
+    /// An operand that is missing, malformed or of the wrong kind
+    InvalidOperand,
+    /// An immediate or displacement that does not fit its encoding
+    OutOfRange,
+    /// Text after the last operand of an instruction
+    TrailingText,
+    /// The code is longer than [`CAPACITY`]
+    TooLong,
+}
+
+impl AsmErrorKind {
+    /// Description of the error, usable in const panics
+    pub const fn message(self) -> &'static str {
+        match self {
+            AsmErrorKind::Empty => "no instructions",
+            AsmErrorKind::UnknownMnemonic => "unknown mnemonic",
+            AsmErrorKind::InvalidOperand => "invalid operand",
+            AsmErrorKind::OutOfRange => "operand out of range",
+            AsmErrorKind::TrailingText => "unexpected text after instruction",
+            AsmErrorKind::TooLong => "code too long",
+        }
+    }
+}
+
+/// An assembly failure and the byte offset in the text where it was detected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AsmError {
+    pub position: usize,
+    pub kind: AsmErrorKind,
+}
+
+impl std::fmt::Display for AsmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} at position {}", self.kind.message(), self.position)
+    }
+}
+
+/// Assembled machine code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Code {
+    bytes: [u8; CAPACITY],
+    len: usize,
+}
+
+impl Code {
+    pub const fn bytes(&self) -> &[u8] {
+        self.bytes.split_at(self.len).0
+    }
+}
+
+/// Assemble `text`, panicking on errors (at compile time in a const context)
+pub const fn assemble(text: &str) -> Code {
+    match try_assemble(text) {
+        Ok(code) => code,
+        Err(e) => panic!("{}", e.kind.message()),
+    }
+}
+
+/// Assemble `text`
+pub const fn try_assemble(text: &str) -> Result<Code, AsmError> {
+    let mut asm = Assembler {
+        text: text.as_bytes(),
+        at: 0,
+        code: Code {
+            bytes: [0; CAPACITY],
+            len: 0,
+        },
+        error: None,
+    };
+
+    loop {
+        // Skip empty instructions
+        asm.spaces();
+        while matches!(asm.peek(), b';' | b'\n') {
+            asm.at += 1;
+            asm.spaces();
+        }
+        if asm.at >= asm.text.len() {
+            break;
+        }
+
+        asm.instruction();
+        if !asm.end_of_instruction() {
+            asm.fail(AsmErrorKind::TrailingText);
+        }
+        if asm.error.is_some() {
+            break;
+        }
+    }
+
+    match asm.error {
+        Some(e) => Err(e),
+        None if asm.code.len == 0 => Err(AsmError {
+            position: 0,
+            kind: AsmErrorKind::Empty,
+        }),
+        None => Ok(asm.code),
+    }
+}
+
+/// A parsed operand
+#[derive(Clone, Copy)]
+enum Operand {
+    Reg8(u8),
+    Reg32(u8),
+    Immediate(i64),
+    Invalid,
+}
+
+/// Parser and encoder state; the first error is kept and stops assembly
+struct Assembler<'a> {
+    text: &'a [u8],
+    at: usize,
+    code: Code,
+    error: Option<AsmError>,
+}
+
+impl Assembler<'_> {
+    const fn instruction(&mut self) {
+        self.spaces();
+        let start = self.at;
+        let mnemonic = self.word();
+
+        if self.is(mnemonic, "nop") {
+            self.emit(0x90);
+        } else if self.is(mnemonic, "int3") {
+            self.emit(0xCC);
+        } else if self.is(mnemonic, "ret") {
+            if self.end_of_instruction() {
+                self.emit(0xC3);
+            } else {
+                let value = self.immediate(0, 0xFFFF);
+                self.emit(0xC2);
+                self.emit_u16(value as u16);
+            }
+        } else if self.is(mnemonic, "push") {
+            match self.operand() {
+                Operand::Reg32(reg) => self.emit(0x50 + reg),
+                Operand::Immediate(value) if value >= -0x80 && value < 0x80 => {
+                    self.emit(0x6A);
+                    self.emit(value as u8);
+                }
+                Operand::Immediate(value) => {
+                    self.check(value, i32::MIN as i64, u32::MAX as i64);
+                    self.emit(0x68);
+                    self.emit_u32(value as u32);
+                }
+                _ => self.fail(AsmErrorKind::InvalidOperand),
+            }
+        } else if self.is(mnemonic, "pop") {
+            self.register_op(0x58);
+        } else if self.is(mnemonic, "inc") {
+            self.register_op(0x40);
+        } else if self.is(mnemonic, "dec") {
+            self.register_op(0x48);
+        } else if self.is(mnemonic, "mov") {
+            let (destination, source) = self.operands();
+            match (destination, source) {
+                (Operand::Reg8(reg), Operand::Immediate(value)) => {
+                    self.check(value, -0x80, 0xFF);
+                    self.emit(0xB0 + reg);
+                    self.emit(value as u8);
+                }
+                (Operand::Reg32(reg), Operand::Immediate(value)) => {
+                    self.check(value, i32::MIN as i64, u32::MAX as i64);
+                    self.emit(0xB8 + reg);
+                    self.emit_u32(value as u32);
+                }
+                _ => self.register_pair(destination, source, 0x8A, false),
+            }
+        } else if self.is(mnemonic, "xor") {
+            let (destination, source) = self.operands();
+            self.register_pair(destination, source, 0x32, false);
+        } else if self.is(mnemonic, "test") {
+            let (destination, source) = self.operands();
+            self.register_pair(destination, source, 0x84, true);
+        } else if self.is(mnemonic, "jmp") {
+            let short = self.keyword("short");
+            self.branch(if short { &[0xEB] } else { &[0xE9] }, short);
+        } else if self.is(mnemonic, "call") {
+            self.branch(&[0xE8], false);
+        } else if let Some(condition) = self.condition(mnemonic) {
+            let short = self.keyword("short");
+            if short {
+                self.branch(&[0x70 | condition], true);
+            } else {
+                self.branch(&[0x0F, 0x80 | condition], false);
+            }
+        } else {
+            self.at = start;
+            self.fail(AsmErrorKind::UnknownMnemonic);
+        }
+    }
+
+    /// `op r32` encoded as `base + reg`
+    const fn register_op(&mut self, base: u8) {
+        match self.operand() {
+            Operand::Reg32(reg) => self.emit(base + reg),
+            _ => self.fail(AsmErrorKind::InvalidOperand),
+        }
+    }
+
+    /// `op r, r` between registers of the same size
+    ///
+    /// `opcode` is the 8-bit form, `opcode + 1` the 32-bit one. The register
+    /// fields follow MSVC: `reg` is the destination for `mov`/`xor` and the
+    /// source for `test`.
+    const fn register_pair(
+        &mut self,
+        destination: Operand,
+        source: Operand,
+        opcode: u8,
+        test: bool,
+    ) {
+        let (opcode, destination, source) = match (destination, source) {
+            (Operand::Reg8(d), Operand::Reg8(s)) => (opcode, d, s),
+            (Operand::Reg32(d), Operand::Reg32(s)) => (opcode + 1, d, s),
+            _ => {
+                self.fail(AsmErrorKind::InvalidOperand);
+                return;
+            }
+        };
+
+        let (reg, rm) = if test {
+            (source, destination)
+        } else {
+            (destination, source)
+        };
+        self.emit(opcode);
+        self.emit(0xC0 | reg << 3 | rm);
+    }
+
+    /// A relative branch with `opcode` and a rel8 or rel32 displacement
+    const fn branch(&mut self, opcode: &[u8], short: bool) {
+        self.spaces();
+        let displacement = if short {
+            self.immediate(i8::MIN as i64, i8::MAX as i64)
+        } else {
+            self.immediate(i32::MIN as i64, i32::MAX as i64)
+        };
+
+        let mut i = 0;
+        while i < opcode.len() {
+            self.emit(opcode[i]);
+            i += 1;
+        }
+        if short {
+            self.emit(displacement as u8);
+        } else {
+            self.emit_u32(displacement as u32);
+        }
+    }
+
+    /// Condition code of a `jcc` mnemonic
+    const fn condition(&self, mnemonic: (usize, usize)) -> Option<u8> {
+        let (start, end) = mnemonic;
+        if end - start < 2 || !self.text[start].eq_ignore_ascii_case(&b'j') {
+            return None;
+        }
+
+        let mut i = 0;
+        while i < CONDITIONS.len() {
+            if self.is((start + 1, end), CONDITIONS[i].0) {
+                return Some(CONDITIONS[i].1);
+            }
+            i += 1;
+        }
+        None
+    }
+
+    /// Two operands separated by a comma
+    const fn operands(&mut self) -> (Operand, Operand) {
+        let destination = self.operand();
+        self.spaces();
+        if self.peek() == b',' {
+            self.at += 1;
+        } else {
+            self.fail(AsmErrorKind::InvalidOperand);
+        }
+        (destination, self.operand())
+    }
+
+    const fn operand(&mut self) -> Operand {
+        self.spaces();
+        if matches!(self.peek(), b'0'..=b'9' | b'+' | b'-') {
+            return Operand::Immediate(self.number());
+        }
+
+        let start = self.at;
+        let name = self.word();
+        let mut i = 0;
+        while i < 8 {
+            if self.is(name, REG32[i]) {
+                return Operand::Reg32(i as u8);
+            }
+            if self.is(name, REG8[i]) {
+                return Operand::Reg8(i as u8);
+            }
+            i += 1;
+        }
+
+        self.at = start;
+        self.fail(AsmErrorKind::InvalidOperand);
+        Operand::Invalid
+    }
+
+    /// A number within `min..=max`
+    const fn immediate(&mut self, min: i64, max: i64) -> i64 {
+        self.spaces();
+        let value = self.number();
+        self.check(value, min, max);
+        value
+    }
+
+    /// A decimal or `0x` hex number with an optional sign
+    const fn number(&mut self) -> i64 {
+        let start = self.at;
+        let negative = self.peek() == b'-';
+        if matches!(self.peek(), b'+' | b'-') {
+            self.at += 1;
+        }
+
+        let mut radix = 10;
+        if self.peek() == b'0' && matches!(self.peek_at(1), b'x' | b'X') {
+            radix = 16;
+            self.at += 2;
+        }
+
+        let mut value: i64 = 0;
+        let mut digits = 0;
+        loop {
+            let digit = match self.peek() {
+                c @ b'0'..=b'9' => c - b'0',
+                c @ b'a'..=b'f' if radix == 16 => c - b'a' + 10,
+                c @ b'A'..=b'F' if radix == 16 => c - b'A' + 10,
+                _ => break,
+            };
+            // Anything this large is out of range for every operand
+            if value <= u32::MAX as i64 {
+                value = value * radix + digit as i64;
+            }
+            digits += 1;
+            self.at += 1;
+        }
+
+        if digits == 0 {
+            self.at = start;
+            self.fail(AsmErrorKind::InvalidOperand);
+        }
+        if negative { -value } else { value }
+    }
+
+    const fn check(&mut self, value: i64, min: i64, max: i64) {
+        if value < min || value > max {
+            self.fail(AsmErrorKind::OutOfRange);
+        }
+    }
+
+    /// Consume `keyword` if it is the next word
+    const fn keyword(&mut self, keyword: &str) -> bool {
+        let start = self.at;
+        let word = self.word();
+        if self.is(word, keyword) {
+            true
+        } else {
+            self.at = start;
+            false
+        }
+    }
+
+    /// The next run of letters and digits, as a range of the text
+    const fn word(&mut self) -> (usize, usize) {
+        self.spaces();
+        let start = self.at;
+        while self.peek().is_ascii_alphanumeric() {
+            self.at += 1;
+        }
+        (start, self.at)
+    }
+
+    /// Whether the text in `word` is `name`, ignoring case
+    const fn is(&self, word: (usize, usize), name: &str) -> bool {
+        let (start, end) = word;
+        let name = name.as_bytes();
+        if end - start != name.len() {
+            return false;
+        }
+
+        let mut i = 0;
+        while i < name.len() {
+            if !self.text[start + i].eq_ignore_ascii_case(&name[i]) {
+                return false;
+            }
+            i += 1;
+        }
+        true
+    }
+
+    const fn end_of_instruction(&mut self) -> bool {
+        self.spaces();
+        self.at >= self.text.len() || matches!(self.peek(), b';' | b'\n')
+    }
+
+    const fn spaces(&mut self) {
+        while matches!(self.peek(), b' ' | b'\t' | b'\r') {
+            self.at += 1;
+        }
+    }
+
+    const fn peek(&self) -> u8 {
+        self.peek_at(0)
+    }
+
+    const fn peek_at(&self, offset: usize) -> u8 {
+        if self.at + offset < self.text.len() {
+            self.text[self.at + offset]
+        } else {
+            0
+        }
+    }
+
+    const fn emit(&mut self, byte: u8) {
+        if self.code.len == CAPACITY {
+            self.fail(AsmErrorKind::TooLong);
+            return;
+        }
+        self.code.bytes[self.code.len] = byte;
+        self.code.len += 1;
+    }
+
+    const fn emit_u16(&mut self, value: u16) {
+        let bytes = value.to_le_bytes();
+        self.emit(bytes[0]);
+        self.emit(bytes[1]);
+    }
+
+    const fn emit_u32(&mut self, value: u32) {
+        let bytes = value.to_le_bytes();
+        let mut i = 0;
+        while i < 4 {
+            self.emit(bytes[i]);
+            i += 1;
+        }
+    }
+
+    /// Record an error at the current position, unless there already is one
+    const fn fail(&mut self, kind: AsmErrorKind) {
+        if self.error.is_none() {
+            self.error = Some(AsmError {
+                position: self.at,
+                kind,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bytes(text: &str) -> Vec<u8> {
+        try_assemble(text)
+            .unwrap_or_else(|e| panic!("{}: {}", text, e))
+            .bytes()
+            .to_vec()
+    }
+
+    fn error(text: &str) -> (usize, AsmErrorKind) {
+        let e = try_assemble(text).unwrap_err();
+        (e.position, e.kind)
+    }
+
+    #[test]
+    fn single_instructions() {
+        assert_eq!(bytes("nop"), [0x90]);
+        assert_eq!(bytes("int3"), [0xCC]);
+        assert_eq!(bytes("ret"), [0xC3]);
+        assert_eq!(bytes("ret 8"), [0xC2, 0x08, 0x00]);
+        assert_eq!(bytes("push esi"), [0x56]);
+        assert_eq!(bytes("push 1"), [0x6A, 0x01]);
+        assert_eq!(bytes("push -0x80"), [0x6A, 0x80]);
+        assert_eq!(bytes("push 0x100"), [0x68, 0x00, 0x01, 0x00, 0x00]);
+        assert_eq!(bytes("pop edi"), [0x5F]);
+        assert_eq!(bytes("inc eax"), [0x40]);
+        assert_eq!(bytes("dec edi"), [0x4F]);
+    }
+
+    #[test]
+    fn register_operands() {
+        assert_eq!(bytes("mov al, bl"), [0x8A, 0xC3]);
+        assert_eq!(bytes("mov ecx, edx"), [0x8B, 0xCA]);
+        assert_eq!(bytes("xor eax, eax"), [0x33, 0xC0]);
+        // test puts the source in the reg field
+        assert_eq!(bytes("test al, cl"), [0x84, 0xC8]);
+        assert_eq!(bytes("test ecx, ecx"), [0x85, 0xC9]);
+        assert_eq!(bytes("mov al, 1"), [0xB0, 0x01]);
+        assert_eq!(bytes("mov bh, -1"), [0xB7, 0xFF]);
+        assert_eq!(bytes("mov eax, 0x12345678"), [0xB8, 0x78, 0x56, 0x34, 0x12]);
+    }
+
+    #[test]
+    fn branches() {
+        assert_eq!(bytes("jmp short +0x0E"), [0xEB, 0x0E]);
+        assert_eq!(bytes("JMP SHORT -2"), [0xEB, 0xFE]);
+        assert_eq!(bytes("jmp +0"), [0xE9, 0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(bytes("call -5"), [0xE8, 0xFB, 0xFF, 0xFF, 0xFF]);
+        assert_eq!(bytes("jnz short +5"), [0x75, 0x05]);
+        assert_eq!(bytes("jne short 5"), [0x75, 0x05]);
+        assert_eq!(bytes("jz -6"), [0x0F, 0x84, 0xFA, 0xFF, 0xFF, 0xFF]);
+        assert_eq!(bytes("jnle short +1"), [0x7F, 0x01]);
+    }
+
+    #[test]
+    fn sequences() {
+        assert_eq!(bytes("xor eax, eax; ret"), [0x33, 0xC0, 0xC3]);
+        assert_eq!(
+            bytes("push 1\npush esi\r\npop edi"),
+            [0x6A, 0x01, 0x56, 0x5F]
+        );
+        assert_eq!(bytes(" nop ; ; int3 ;"), [0x90, 0xCC]);
+        assert_eq!(bytes(&"nop;".repeat(CAPACITY)), [0x90; CAPACITY]);
+
+        const CODE: Code = assemble("mov al, 1");
+        assert_eq!(CODE.bytes(), [0xB0, 0x01]);
+    }
+
+    #[test]
+    fn errors_report_their_position() {
+        assert_eq!(error(""), (0, AsmErrorKind::Empty));
+        assert_eq!(error(" ; \n"), (0, AsmErrorKind::Empty));
+        assert_eq!(error("nop; foo eax"), (5, AsmErrorKind::UnknownMnemonic));
+        assert_eq!(error("push xyz"), (5, AsmErrorKind::InvalidOperand));
+        // Mismatched sizes are only known once both operands are read
+        assert_eq!(error("mov al, ebx"), (11, AsmErrorKind::InvalidOperand));
+        assert_eq!(error("mov al 1"), (7, AsmErrorKind::InvalidOperand));
+        assert_eq!(error("call short +1"), (5, AsmErrorKind::InvalidOperand));
+        assert_eq!(error("jmp short +0x80"), (15, AsmErrorKind::OutOfRange));
+        assert_eq!(error("mov al, 256"), (11, AsmErrorKind::OutOfRange));
+        assert_eq!(error("ret 0x10000"), (11, AsmErrorKind::OutOfRange));
+        assert_eq!(error("nop nop"), (4, AsmErrorKind::TrailingText));
+        // At the end of the nop that did not fit
+        assert_eq!(
+            error(&"nop;".repeat(CAPACITY + 1)),
+            (CAPACITY * 4 + 3, AsmErrorKind::TooLong)
+        );
+    }
+}
@@ -0,0 +1,218 @@
+//! Dunia.dll build identification
+//!
+//! Far Cry 2 shipped several builds of Dunia.dll (retail 1.00/1.02/1.03, Steam,
+//! GOG, Uplay). A build is identified by its PE timestamp and checksum together
+//! with a hash of its code sections, so a modified image is never mistaken for
+//! a known build. Relocated operands are hashed with their file values, so the
+//! hash does not depend on where the loader placed the module.
+//!
+//! Builds listed in [`KNOWN_BUILDS`] take their patch addresses from a table of
+//! verified RVAs instead of scanning. Every table entry is still checked
+//! against its signature before use, and anything missing or stale falls back
+//! to scanning. The table is empty until builds have been checked against a
+//! real copy of their Dunia.dll, so for now every build is identified (and
+//! reported) but scanned.
+
+use crate::patches::pe::Module;
+
+/// What identifies a build of Dunia.dll
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BuildId {
+    pub timestamp: u32,
+    pub checksum: u32,
+    /// FNV-1a hash of the executable sections
+    pub code_hash: u64,
+}
+
+impl BuildId {
+    /// Identify a module
+    ///
+    /// The code hash covers the module as it is mapped, so it must be taken
+    /// before any patches are applied. If the module was loaded away from its
+    /// preferred base, every relocated value in the code is moved back to that
+    /// base first, so the hash is the same as that of the file on disk.
+    pub fn of(module: &Module) -> Self {
+        let delta = module.base.wrapping_sub(module.headers.image_base as usize) as u32;
+        let relocations = if delta != 0 {
+            module.relocations()
+        } else {
+            Vec::new()
+        };
+
+        let code_hash = module
+            .headers
+            .sections
+            .iter()
+            .filter(|section| section.is_executable())
+            .fold(FNV_OFFSET_BASIS, |hash, section| {
+                let data = module.section_data(section);
+                if relocations.is_empty() {
+                    return fnv1a(hash, data);
+                }
+
+                let start = module.base + section.virtual_address as usize;
+                let mut code = data.to_vec();
+                for &fixup in &relocations {
+                    let Some(offset) = fixup.checked_sub(start) else {
+                        continue;
+                    };
+                    let Some(value) = code.get_mut(offset..offset + 4) else {
+                        continue;
+                    };
+                    let original = u32::from_le_bytes([value[0], value[1], value[2], value[3]])
+                        .wrapping_sub(delta);
+                    value.copy_from_slice(&original.to_le_bytes());
+                }
+                fnv1a(hash, &code)
+            });
+
+        Self {
+            timestamp: module.headers.time_date_stamp,
+            checksum: module.headers.checksum,
+            code_hash,
+        }
+    }
+}
+
+impl std::fmt::Display for BuildId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "timestamp 0x{:08X}, checksum 0x{:08X}, code hash 0x{:016X}",
+            self.timestamp, self.checksum, self.code_hash
+        )
+    }
+}
+
+/// A build of Dunia.dll with verified patch addresses
+pub struct KnownBuild {
+    pub name: &'static str,
+    pub id: BuildId,
+    /// Signature name and RVA of its match
+    pub addresses: &'static [(&'static str, u32)],
+    /// Signature name and the original bytes a patch replaces there, for
+    /// patches whose original bytes differ between builds
+    pub originals: &'static [(&'static str, &'static [u8])],
+}
+
+impl KnownBuild {
+    /// Address of the signature `name` in `module`, if the table has it
+    pub fn address(&self, module: &Module, name: &str) -> Option<usize> {
+        self.addresses
+            .iter()
+            .find(|(entry, _)| *entry == name)
+            .map(|&(_, rva)| module.base + rva as usize)
+    }
+
+    /// Original bytes at the site of the signature `name`, if the table has them
+    pub fn original(&self, name: &str) -> Option<&'static [u8]> {
+        self.originals
+            .iter()
+            .find(|(entry, _)| *entry == name)
+            .map(|&(_, bytes)| bytes)
+    }
+}
+
+/// Builds with verified addresses
+///
+/// No build has been verified yet, so every build is scanned. To add one, run
+/// `dunia-tool scan` on its Dunia.dll and copy the build id and the RVA of each
+/// signature it reports into a new entry, along with the unpatched Jackal
+/// Tapes displacement from a clean copy of that build.
+const KNOWN_BUILDS: &[KnownBuild] = &[];
+
+/// The known build matching `id`, if any
+pub fn identify(id: &BuildId) -> Option<&'static KnownBuild> {
+    KNOWN_BUILDS.iter().find(|build| build.id == *id)
+}
+
+/// FNV-1a hash of `data`
+pub fn content_hash(data: &[u8]) -> u64 {
+    fnv1a(FNV_OFFSET_BASIS, data)
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xCBF2_9CE4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01B3;
+
+fn fnv1a(hash: u64, data: &[u8]) -> u64 {
+    data.iter().fold(hash, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::patches::fixture::{self, CODE, IMAGE_BASE, RDATA};
+    use crate::patches::pe::IMAGE_DIRECTORY_ENTRY_BASERELOC;
+
+    /// Code with two absolute addresses (`push` and `mov eax, [imm32]`) and
+    /// the base relocations for them
+    fn file(code_byte: u8) -> Vec<u8> {
+        let global = (IMAGE_BASE as u32 + 0x1080).to_le_bytes();
+        let mut code = vec![code_byte; 0x100];
+        code[0] = 0x68;
+        code[1..5].copy_from_slice(&global);
+        code[0x10] = 0xA1;
+        code[0x11..0x15].copy_from_slice(&global);
+
+        // { page rva, block size, HIGHLOW entries, padding }
+        let mut reloc = vec![0u8; 0x10];
+        reloc[..4].copy_from_slice(&0x1000u32.to_le_bytes());
+        reloc[4..8].copy_from_slice(&0x10u32.to_le_bytes());
+        reloc[8..10].copy_from_slice(&0x3001u16.to_le_bytes());
+        reloc[10..12].copy_from_slice(&0x3011u16.to_le_bytes());
+
+        fixture::file(
+            &[
+                (".text", 0x1000, code, CODE),
+                (".reloc", 0x2000, reloc, RDATA),
+            ],
+            &[(IMAGE_DIRECTORY_ENTRY_BASERELOC, 0x2000, 0x10)],
+        )
+    }
+
+    #[test]
+    fn hash_does_not_depend_on_the_load_address() {
+        let file = file(0x90);
+        let at_image_base = Module::from_file(&file).unwrap();
+        let mut rebased = Module::from_file(&file).unwrap();
+        rebased.rebase(0x2340_0000);
+
+        let text = &rebased.headers.sections[0];
+        assert_ne!(
+            rebased.section_data(text),
+            at_image_base.section_data(text),
+            "rebasing changes the code"
+        );
+        assert_eq!(BuildId::of(&rebased), BuildId::of(&at_image_base));
+    }
+
+    #[test]
+    fn hash_covers_the_code() {
+        let original = Module::from_file(&file(0x90)).unwrap();
+        let modified = Module::from_file(&file(0xCC)).unwrap();
+
+        assert_ne!(
+            BuildId::of(&original).code_hash,
+            BuildId::of(&modified).code_hash
+        );
+        assert_eq!(
+            BuildId::of(&original).timestamp,
+            BuildId::of(&modified).timestamp
+        );
+    }
+
+    #[test]
+    fn unknown_builds_are_not_identified() {
+        let id = BuildId::of(&Module::from_file(&file(0x90)).unwrap());
+        assert!(identify(&id).is_none());
+    }
+
+    #[test]
+    fn content_hash_is_fnv1a() {
+        assert_eq!(content_hash(b""), 0xCBF2_9CE4_8422_2325);
+        assert_eq!(content_hash(b"a"), 0xAF63_DC4C_8601_EC8C);
+        assert_eq!(content_hash(b"foobar"), 0x8594_4171_F739_67E8);
+    }
+}
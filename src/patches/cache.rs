@@ -0,0 +1,230 @@
+//! Persistent cache of resolved signature addresses
+//!
+//! Scanning runs inside `DllMain` on every launch. The addresses it finds for
+//! patch and hook signatures are saved as RVAs in `systemdetection.cache` next
+//! to systemdetection.dll, keyed by the [`BuildId`] of Dunia.dll, and tried
+//! first on the next launch. A cached address is only used if its signature
+//! still matches there, so a stale entry costs a rescan of that signature,
+//! never a bad patch.
+//!
+//! The file is plain text ending in a checksum line:
+//!
+//! ```text
+//! systemdetection scan cache 1
+//! build 49006B4A 00000000 0123456789ABCDEF
+//! 0002F3A0 Jackal Tapes
+//! 0012A4C0 InitOptions
+//! checksum 0123456789ABCDEF
+//! ```
+//!
+//! A file for another build, another format version or with a bad checksum is
+//! ignored and rewritten after the scan.
+
+use crate::patches::builds::{BuildId, content_hash};
+use crate::patches::pe::Module;
+use std::path::{Path, PathBuf};
+use windows::Win32::Foundation::{HMODULE, MAX_PATH};
+use windows::Win32::System::LibraryLoader::GetModuleFileNameW;
+
+const HEADER: &str = "systemdetection scan cache 1";
+const FILE_NAME: &str = "systemdetection.cache";
+
+/// Signature addresses resolved for one build of Dunia.dll
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScanCache {
+    pub build: BuildId,
+    /// Signature name and RVA of its match
+    pub addresses: Vec<(String, u32)>,
+}
+
+impl ScanCache {
+    /// Location of the cache file, next to the DLL `module`
+    pub fn path(module: HMODULE) -> Option<PathBuf> {
+        beside(module, FILE_NAME)
+    }
+
+    /// Load the cache for `build`, or None if it is missing, corrupt or for another build
+    pub fn load(path: &Path, build: &BuildId) -> Option<Self> {
+        let text = std::fs::read_to_string(path).ok()?;
+        let cache = Self::parse(&text)?;
+        (cache.build == *build).then_some(cache)
+    }
+
+    /// Write the cache, replacing any previous file
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        std::fs::write(path, self.to_text())
+    }
+
+    /// Address of the signature `name` in `module`, if cached
+    pub fn address(&self, module: &Module, name: &str) -> Option<usize> {
+        self.addresses
+            .iter()
+            .find(|(entry, _)| entry == name)
+            .map(|&(_, rva)| module.base + rva as usize)
+    }
+
+    fn parse(text: &str) -> Option<Self> {
+        // An editor may have converted the line endings, which is not corruption
+        let text = text.replace("\r\n", "\n");
+
+        // The checksum covers everything before the checksum line
+        let (body, checksum) = text.trim_end().rsplit_once('\n')?;
+        let checksum = u64::from_str_radix(checksum.strip_prefix("checksum ")?, 16).ok()?;
+        if content_hash(format!("{}\n", body).as_bytes()) != checksum {
+            return None;
+        }
+
+        let mut lines = body.lines();
+        if lines.next()? != HEADER {
+            return None;
+        }
+
+        let build = lines.next()?.strip_prefix("build ")?;
+        let mut fields = build.split(' ');
+        let build = BuildId {
+            timestamp: u32::from_str_radix(fields.next()?, 16).ok()?,
+            checksum: u32::from_str_radix(fields.next()?, 16).ok()?,
+            code_hash: u64::from_str_radix(fields.next()?, 16).ok()?,
+        };
+
+        let addresses = lines
+            .map(|line| {
+                let (rva, name) = line.split_once(' ')?;
+                Some((name.to_string(), u32::from_str_radix(rva, 16).ok()?))
+            })
+            .collect::<Option<Vec<_>>>()?;
+
+        Some(Self { build, addresses })
+    }
+
+    fn to_text(&self) -> String {
+        let mut text = format!(
+            "{}\nbuild {:08X} {:08X} {:016X}\n",
+            HEADER, self.build.timestamp, self.build.checksum, self.build.code_hash
+        );
+        for (name, rva) in &self.addresses {
+            text += &format!("{:08X} {}\n", rva, name);
+        }
+
+        let checksum = content_hash(text.as_bytes());
+        text + &format!("checksum {:016X}\n", checksum)
+    }
+}
+
+/// Path of the file `name` in the directory of the DLL `module`
+pub fn beside(module: HMODULE, name: &str) -> Option<PathBuf> {
+    let mut buffer = [0u16; MAX_PATH as usize];
+    let len = unsafe { GetModuleFileNameW(Some(module), &mut buffer) } as usize;
+    if len == 0 || len == buffer.len() {
+        return None;
+    }
+
+    let dll = PathBuf::from(String::from_utf16_lossy(&buffer[..len]));
+    Some(dll.with_file_name(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BUILD: BuildId = BuildId {
+        timestamp: 0x4900_6B4A,
+        checksum: 0,
+        code_hash: 0x0123_4567_89AB_CDEF,
+    };
+
+    fn cache() -> ScanCache {
+        ScanCache {
+            build: BUILD,
+            addresses: vec![
+                ("Jackal Tapes".to_string(), 0x2F3A0),
+                ("InitOptions".to_string(), 0x12_A4C0),
+            ],
+        }
+    }
+
+    /// `body` followed by its checksum line
+    fn with_checksum(body: &str) -> String {
+        format!("{}checksum {:016X}\n", body, content_hash(body.as_bytes()))
+    }
+
+    #[test]
+    fn round_trips() {
+        let text = cache().to_text();
+
+        assert_eq!(
+            text,
+            with_checksum(
+                "systemdetection scan cache 1\n\
+                 build 49006B4A 00000000 0123456789ABCDEF\n\
+                 0002F3A0 Jackal Tapes\n\
+                 0012A4C0 InitOptions\n"
+            )
+        );
+        assert_eq!(ScanCache::parse(&text), Some(cache()));
+
+        let empty = ScanCache {
+            build: BUILD,
+            addresses: Vec::new(),
+        };
+        assert_eq!(ScanCache::parse(&empty.to_text()), Some(empty));
+    }
+
+    #[test]
+    fn rejects_a_bad_checksum() {
+        let text = cache().to_text().replace("0002F3A0", "0002F3B0");
+        assert_eq!(ScanCache::parse(&text), None);
+
+        let text = cache().to_text().replace("checksum ", "checksum x");
+        assert_eq!(ScanCache::parse(&text), None);
+    }
+
+    #[test]
+    fn rejects_another_format() {
+        let body = cache().to_text();
+        let body = &body[..body.find("checksum").unwrap()];
+
+        let other_version = body.replace("scan cache 1", "scan cache 2");
+        assert_eq!(ScanCache::parse(&with_checksum(&other_version)), None);
+        let no_header = body.replace("systemdetection scan cache 1\n", "");
+        assert_eq!(ScanCache::parse(&with_checksum(&no_header)), None);
+        let bad_entry = body.replace("0002F3A0 Jackal Tapes", "Jackal Tapes");
+        assert_eq!(ScanCache::parse(&with_checksum(&bad_entry)), None);
+        let bad_build = body.replace("00000000 ", "");
+        assert_eq!(ScanCache::parse(&with_checksum(&bad_build)), None);
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        let text = cache().to_text();
+
+        for len in 0..text.len() - 1 {
+            assert_eq!(ScanCache::parse(&text[..len]), None, "{:?}", &text[..len]);
+        }
+    }
+
+    #[test]
+    fn accepts_crlf_line_endings() {
+        let text = cache().to_text().replace('\n', "\r\n");
+        assert_eq!(ScanCache::parse(&text), Some(cache()));
+    }
+
+    #[test]
+    fn load_ignores_other_builds() {
+        let path =
+            std::env::temp_dir().join(format!("systemdetection-{}.cache", std::process::id()));
+        cache().save(&path).unwrap();
+        let other = BuildId {
+            code_hash: 0,
+            ..BUILD
+        };
+
+        let loaded = ScanCache::load(&path, &BUILD);
+        let for_other = ScanCache::load(&path, &other);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded, Some(cache()));
+        assert_eq!(for_other, None);
+        assert_eq!(ScanCache::load(&path, &BUILD), None);
+    }
+}
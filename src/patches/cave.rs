@@ -0,0 +1,275 @@
+//! Executable memory near a module for trampolines and detours
+//!
+//! A `jmp`/`call` rel32 only reaches ±2 GB, so code that game code branches to
+//! has to live near the game module. Blocks are carved, 16-byte aligned, from
+//! caves of two kinds:
+//!
+//! - the slack between the end of an executable section and the end of its
+//!   last page, which the loader maps executable and fills with zeros
+//! - 64 KB allocations reserved as close to the module as possible
+//!
+//! Blocks are never freed individually. [`release`] gives every cave back at
+//! once, and must only be called once nothing branches into them any more.
+
+use crate::patches::memory;
+use crate::patches::pe::Module;
+use std::ffi::c_void;
+use std::sync::Mutex;
+use windows::Win32::System::Memory::{
+    MEM_COMMIT, MEM_FREE, MEM_RELEASE, MEM_RESERVE, MEMORY_BASIC_INFORMATION,
+    PAGE_EXECUTE_READWRITE, VirtualAlloc, VirtualFree, VirtualQuery,
+};
+
+/// Alignment of every block
+const BLOCK_ALIGN: usize = 16;
+/// Size of a reserved cave, the allocation granularity of Windows
+const CAVE_SIZE: usize = 0x10000;
+/// Page size, the granularity of section mappings
+const PAGE_SIZE: usize = 0x1000;
+/// Furthest a cave may be from the module it serves, leaving room for the
+/// module itself within the reach of rel32
+const MAX_DISTANCE: usize = 0x7FF0_0000;
+
+/// Reasons no block could be allocated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaveError {
+    /// No free memory within rel32 range of the module
+    NoSpace(usize),
+}
+
+impl std::fmt::Display for CaveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CaveError::NoSpace(size) => {
+                write!(
+                    f,
+                    "no executable memory for {} bytes within rel32 range",
+                    size
+                )
+            }
+        }
+    }
+}
+
+/// A range of executable memory handed out block by block
+struct Cave {
+    start: usize,
+    end: usize,
+    /// First byte not yet handed out
+    next: usize,
+    /// True if we allocated the memory, false for section slack
+    owned: bool,
+}
+
+impl Cave {
+    /// Carve `size` bytes if they fit and are in range of `low..high`
+    fn carve(&mut self, size: usize, low: usize, high: usize) -> Option<usize> {
+        let block = self.next.next_multiple_of(BLOCK_ALIGN);
+        let end = block.checked_add(size)?;
+        if end > self.end || !reachable(block, end, low, high) {
+            return None;
+        }
+
+        self.next = end;
+        Some(block)
+    }
+}
+
+static CAVES: Mutex<Vec<Cave>> = Mutex::new(Vec::new());
+
+/// Allocate `size` bytes of executable memory within rel32 range of `module`
+///
+/// `module` must be loaded in this process. The block is zero-filled and
+/// [`BLOCK_ALIGN`]-aligned; write to it with [`memory::write_bytes`], which
+/// handles the slack caves' read-only protection.
+#[allow(dead_code)]
+pub fn alloc_near(module: &Module, size: usize) -> Result<usize, CaveError> {
+    let low = module.base;
+    let high = module.base + module.headers.size_of_image as usize;
+    let mut caves = CAVES.lock().unwrap_or_else(|e| e.into_inner());
+
+    if let Some(block) = caves
+        .iter_mut()
+        .find_map(|cave| cave.carve(size, low, high))
+    {
+        return Ok(block);
+    }
+
+    // Section slack is tried once per module, when the first block is needed
+    if !caves
+        .iter()
+        .any(|cave| cave.start >= low && cave.end <= high)
+    {
+        for (start, end) in slack(module) {
+            #[cfg(debug_assertions)]
+            println!("patches: Using 0x{:08X}..0x{:08X} as code cave", start, end);
+
+            caves.push(Cave {
+                start,
+                end,
+                next: start,
+                owned: false,
+            });
+        }
+
+        if let Some(block) = caves
+            .iter_mut()
+            .find_map(|cave| cave.carve(size, low, high))
+        {
+            return Ok(block);
+        }
+    }
+
+    let cave_size = size.next_multiple_of(CAVE_SIZE);
+    let start = reserve_near(low, high, cave_size).ok_or(CaveError::NoSpace(size))?;
+
+    #[cfg(debug_assertions)]
+    println!(
+        "patches: Reserved 0x{:08X}..0x{:08X} as code cave",
+        start,
+        start + cave_size
+    );
+
+    let mut cave = Cave {
+        start,
+        end: start + cave_size,
+        next: start,
+        owned: true,
+    };
+    let block = cave
+        .carve(size, low, high)
+        .ok_or(CaveError::NoSpace(size))?;
+    caves.push(cave);
+    Ok(block)
+}
+
+/// Free every reserved cave and zero the used slack
+///
+/// Anything that branches into a cave must have been reverted first.
+pub fn release() {
+    let mut caves = CAVES.lock().unwrap_or_else(|e| e.into_inner());
+
+    for cave in caves.drain(..) {
+        if cave.owned {
+            if unsafe { VirtualFree(cave.start as *mut c_void, 0, MEM_RELEASE) }.is_err() {
+                #[cfg(debug_assertions)]
+                println!("patches: Failed to free cave 0x{:08X}", cave.start);
+            }
+        } else if cave.next > cave.start
+            && let Err(_e) = memory::write_bytes(cave.start, &vec![0; cave.next - cave.start])
+        {
+            #[cfg(debug_assertions)]
+            println!("patches: Failed to clear cave 0x{:08X}: {}", cave.start, _e);
+        }
+    }
+}
+
+/// True if every byte of `start..end` can reach and be reached from `low..high` with rel32
+fn reachable(start: usize, end: usize, low: usize, high: usize) -> bool {
+    end.saturating_sub(low) <= MAX_DISTANCE && high.saturating_sub(start) <= MAX_DISTANCE
+}
+
+/// Unused, zero-filled tails of the executable sections of a loaded module
+fn slack(module: &Module) -> Vec<(usize, usize)> {
+    let sections = &module.headers.sections;
+    let image_end = module.headers.size_of_image as usize;
+
+    sections
+        .iter()
+        .filter(|section| section.is_executable())
+        .filter_map(|section| {
+            let start = section.virtual_address as usize + section.mapped_size() as usize;
+
+            // The page's remainder, unless another section is mapped into it
+            let next_section = sections
+                .iter()
+                .map(|other| other.virtual_address as usize)
+                .filter(|&other| other >= start)
+                .min()
+                .unwrap_or(image_end);
+            let end = start.next_multiple_of(PAGE_SIZE).min(next_section);
+
+            let start = (module.base + start).next_multiple_of(BLOCK_ALIGN);
+            let end = module.base + end;
+            if end <= start {
+                return None;
+            }
+
+            // Raw data past VirtualSize is mapped too; only use it if it is padding
+            let bytes = unsafe { std::slice::from_raw_parts(start as *const u8, end - start) };
+            bytes.iter().all(|&b| b == 0).then_some((start, end))
+        })
+        .collect()
+}
+
+/// Reserve and commit `size` executable bytes as close to `low..high` as possible
+///
+/// Walks the address space outwards from the module in both directions at
+/// once, skipping over allocated regions, and takes the first free one that
+/// the allocation fits in.
+fn reserve_near(low: usize, high: usize, size: usize) -> Option<usize> {
+    let mut above = Some(high.next_multiple_of(CAVE_SIZE));
+    let mut below = (low - low % CAVE_SIZE).checked_sub(CAVE_SIZE);
+
+    while above.is_some() || below.is_some() {
+        if let Some(address) = above {
+            above = match query(address) {
+                Some(info) if reachable(address, address + size, low, high) => {
+                    if info.State == MEM_FREE
+                        && let Some(start) = allocate(address, size)
+                    {
+                        return Some(start);
+                    }
+                    let region_end = info.BaseAddress as usize + info.RegionSize;
+                    Some(
+                        region_end
+                            .next_multiple_of(CAVE_SIZE)
+                            .max(address + CAVE_SIZE),
+                    )
+                }
+                _ => None,
+            };
+        }
+
+        if let Some(address) = below {
+            below = match query(address) {
+                Some(info) if reachable(address, address + size, low, high) => {
+                    if info.State == MEM_FREE
+                        && let Some(start) = allocate(address, size)
+                    {
+                        return Some(start);
+                    }
+                    // Step below the whole allocation this address belongs to
+                    let base = if info.State == MEM_FREE {
+                        address
+                    } else {
+                        info.AllocationBase as usize
+                    };
+                    (base - base % CAVE_SIZE).checked_sub(CAVE_SIZE)
+                }
+                _ => None,
+            };
+        }
+    }
+
+    None
+}
+
+fn query(address: usize) -> Option<MEMORY_BASIC_INFORMATION> {
+    let mut info = MEMORY_BASIC_INFORMATION::default();
+    let size = std::mem::size_of::<MEMORY_BASIC_INFORMATION>();
+    let written = unsafe { VirtualQuery(Some(address as *const c_void), &mut info, size) };
+    (written != 0).then_some(info)
+}
+
+fn allocate(address: usize, size: usize) -> Option<usize> {
+    let ptr = unsafe {
+        VirtualAlloc(
+            Some(address as *const c_void),
+            size,
+            MEM_COMMIT | MEM_RESERVE,
+            PAGE_EXECUTE_READWRITE,
+        )
+    };
+    (!ptr.is_null()).then_some(ptr as usize)
+}
@@ -0,0 +1,96 @@
+//! User settings, persisted in `systemdetection.ini` next to systemdetection.dll
+//!
+//! One `key = value` per line; blank lines and lines starting with `#` are
+//! ignored. Keys this version does not know are kept, so saving never drops
+//! settings written by another version. Settings are saved as soon as they
+//! change.
+//!
+//! ```text
+//! patch.DevMode = false
+//! patch.No Blinking Items = true
+//! ```
+
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+pub const FILE_NAME: &str = "systemdetection.ini";
+
+/// Settings in file order
+struct Config {
+    /// Where to save, once loaded
+    path: Option<PathBuf>,
+    entries: Vec<(String, String)>,
+}
+
+static CONFIG: Mutex<Config> = Mutex::new(Config {
+    path: None,
+    entries: Vec::new(),
+});
+
+/// Load the settings from `path`, which need not exist yet
+pub fn load(path: &Path) {
+    let entries = std::fs::read_to_string(path)
+        .map(|text| parse(&text))
+        .unwrap_or_default();
+
+    #[cfg(debug_assertions)]
+    println!(
+        "config: Loaded {} settings from {}",
+        entries.len(),
+        path.display()
+    );
+
+    *CONFIG.lock().unwrap_or_else(|e| e.into_inner()) = Config {
+        path: Some(path.to_path_buf()),
+        entries,
+    };
+}
+
+/// The setting `key`, if present and valid
+pub fn get<T: FromStr>(key: &str) -> Option<T> {
+    let config = CONFIG.lock().unwrap_or_else(|e| e.into_inner());
+    config
+        .entries
+        .iter()
+        .find(|(name, _)| name == key)
+        .and_then(|(_, value)| value.parse().ok())
+}
+
+/// Change the setting `key` and save
+#[allow(dead_code)]
+pub fn set(key: &str, value: impl std::fmt::Display) {
+    let mut config = CONFIG.lock().unwrap_or_else(|e| e.into_inner());
+    let value = value.to_string();
+
+    match config.entries.iter_mut().find(|(name, _)| name == key) {
+        Some((_, old)) if *old == value => return,
+        Some((_, old)) => *old = value,
+        None => config.entries.push((key.to_string(), value)),
+    }
+
+    if let Some(path) = &config.path
+        && let Err(_e) = std::fs::write(path, to_text(&config.entries))
+    {
+        #[cfg(debug_assertions)]
+        println!("config: Failed to save {}: {}", path.display(), _e);
+    }
+}
+
+fn parse(text: &str) -> Vec<(String, String)> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let (key, value) = line.split_once('=')?;
+            Some((key.trim().to_string(), value.trim().to_string()))
+        })
+        .collect()
+}
+
+fn to_text(entries: &[(String, String)]) -> String {
+    entries
+        .iter()
+        .map(|(key, value)| format!("{} = {}\n", key, value))
+        .collect()
+}
@@ -0,0 +1,92 @@
+//! Synthetic PE images for tests
+//!
+//! [`file`] lays out a minimal 32-bit PE file: DOS header, NT headers with a
+//! full data directory, one section table entry per section and the raw data
+//! of each section at 0x200-aligned file offsets. [`module`] maps it the way
+//! the loader would, at [`IMAGE_BASE`].
+
+use crate::patches::pe::Module;
+
+pub const IMAGE_BASE: usize = 0x1000_0000;
+
+/// File offset of the "PE\0\0" signature
+pub const NT: usize = 0x80;
+/// File offset of the optional header
+pub const OPTIONAL: usize = NT + 4 + 20;
+/// File offset of the first section table entry
+pub const SECTION_TABLE: usize = OPTIONAL + 0xE0;
+
+/// Characteristics of a code section (code, execute, read)
+pub const CODE: u32 = 0x6000_0020;
+/// Characteristics of a read-only data section (initialized data, read)
+pub const RDATA: u32 = 0x4000_0040;
+/// Characteristics of a writable data section (initialized data, read, write)
+pub const DATA: u32 = 0xC000_0040;
+
+/// A section: name, RVA, raw data (also its virtual size) and characteristics
+pub type SectionSpec<'a> = (&'a str, u32, Vec<u8>, u32);
+
+/// A PE file with `sections` and the data directory entries `directories`
+/// (index, RVA, size)
+pub fn file(sections: &[SectionSpec], directories: &[(usize, u32, u32)]) -> Vec<u8> {
+    let mut out = vec![0u8; 0x400];
+    out[..2].copy_from_slice(b"MZ");
+    put_u32(&mut out, 0x3C, NT as u32);
+    out[NT..NT + 4].copy_from_slice(b"PE\0\0");
+
+    // IMAGE_FILE_HEADER: machine, section count, optional header size
+    put_u16(&mut out, NT + 4, 0x14C);
+    put_u16(&mut out, NT + 6, sections.len() as u16);
+    put_u16(&mut out, NT + 20, 0xE0);
+
+    let image_end = sections
+        .iter()
+        .map(|(_, rva, data, _)| *rva as usize + data.len())
+        .max()
+        .unwrap_or(0x1000);
+    put_u16(&mut out, OPTIONAL, 0x10B);
+    put_u32(&mut out, OPTIONAL + 28, IMAGE_BASE as u32);
+    put_u32(
+        &mut out,
+        OPTIONAL + 56,
+        image_end.next_multiple_of(0x1000) as u32,
+    );
+    put_u32(&mut out, OPTIONAL + 60, 0x400);
+    put_u32(&mut out, OPTIONAL + 92, 16);
+    for &(index, rva, size) in directories {
+        put_u32(&mut out, OPTIONAL + 96 + index * 8, rva);
+        put_u32(&mut out, OPTIONAL + 100 + index * 8, size);
+    }
+
+    let mut entry = SECTION_TABLE;
+    for (name, rva, data, characteristics) in sections {
+        let raw_offset = out.len();
+        let raw_size = data.len().next_multiple_of(0x200);
+
+        out[entry..entry + name.len()].copy_from_slice(name.as_bytes());
+        put_u32(&mut out, entry + 8, data.len() as u32);
+        put_u32(&mut out, entry + 12, *rva);
+        put_u32(&mut out, entry + 16, raw_size as u32);
+        put_u32(&mut out, entry + 20, raw_offset as u32);
+        put_u32(&mut out, entry + 36, *characteristics);
+
+        out.resize(raw_offset + raw_size, 0);
+        out[raw_offset..raw_offset + data.len()].copy_from_slice(data);
+        entry += 40;
+    }
+
+    out
+}
+
+/// [`file`] mapped at [`IMAGE_BASE`]
+pub fn module(sections: &[SectionSpec], directories: &[(usize, u32, u32)]) -> Module {
+    Module::from_file(&file(sections, directories)).expect("fixture is a valid PE file")
+}
+
+pub fn put_u16(data: &mut [u8], offset: usize, value: u16) {
+    data[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+pub fn put_u32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
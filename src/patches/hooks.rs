@@ -1,62 +1,349 @@
 //! Function hooks for Dunia.dll using MinHook
 //!
-//! This module provides detouring/hooking functionality to intercept
-//! game functions and inject custom behavior.
+//! Each hook is declared once in [`HOOKS`] with the [`hook!`] macro: the
+//! signature of the function to detour, the Rust detour and the [`Original`]
+//! static that receives the trampoline to the original function. The detour
+//! and the static share one function pointer type, which carries the calling
+//! convention, so a mismatch is a compile error:
 //!
-//! Currently no hooks are active - this is infrastructure for future use.
+//! ```ignore
+//! static INIT_OPTIONS: Original<extern "thiscall" fn(*mut c_void) -> u8> = Original::new();
+//!
+//! extern "thiscall" fn init_options(this: *mut c_void) -> u8 {
+//!     INIT_OPTIONS.get().map_or(0, |original| original(this))
+//! }
+//!
+//! static HOOKS: &[HookDef] = &[hook!(signatures::INIT_OPTIONS, init_options, INIT_OPTIONS)];
+//! ```
+//!
+//! Hook signatures are resolved in the same batch as the patch signatures,
+//! before anything is written.
 
-#[allow(unused_imports)]
-use crate::patches::sigscan::{Pattern, scan_module};
-#[allow(unused_imports)]
+use crate::patches::sigscan::{Match, ScanError, Signature};
 use minhook::{MH_STATUS, MinHook};
-#[allow(unused_imports)]
 use std::ffi::c_void;
-#[allow(unused_imports)]
-use std::sync::OnceLock;
+use std::marker::PhantomData;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 /// Signature definitions for hookable functions
 #[allow(dead_code)]
-mod signatures {
+pub mod signatures {
+    use super::Signature;
+
     // CFCXOptionGamePage::InitOptions - Game options page initialization
     // sub esp, 160h | push ebx | push ebp | push esi | push edi | xor ebx, ebx
-    pub const INIT_OPTIONS: &str = "81 EC 60 01 00 00 53 55 56 57 33 DB";
+    pub const INIT_OPTIONS: Signature =
+        Signature::code("InitOptions", "81 EC 60 01 00 00 53 55 56 57 33 DB");
 
     // CreateSliderOption - Creates a slider widget
     // mov eax, [esp+1Ch] | push ebx | push esi | mov esi, [esp+0Ch]
-    pub const CREATE_SLIDER: &str = "8B 44 24 1C 53 56 8B 74 24 0C";
+    pub const CREATE_SLIDER: Signature =
+        Signature::code("CreateSliderOption", "8B 44 24 1C 53 56 8B 74 24 0C");
 }
 
-/// Cached function addresses found via signature scanning
-#[allow(dead_code)]
-pub struct HookAddresses {
-    // Reserved for future hooks
+/// The trampoline to a hooked function's original code, typed as the function
+///
+/// `F` is the function pointer type of the hooked function, including its
+/// calling convention (usually `extern "thiscall"` or `extern "C"`).
+pub struct Original<F> {
+    address: AtomicUsize,
+    function: PhantomData<F>,
+}
+
+impl<F: Copy> Original<F> {
+    #[allow(dead_code)]
+    pub const fn new() -> Self {
+        Self {
+            address: AtomicUsize::new(0),
+            function: PhantomData,
+        }
+    }
+
+    /// The original function, or `None` while the hook is not installed
+    #[allow(dead_code)]
+    pub fn get(&self) -> Option<F> {
+        const { assert!(size_of::<F>() == size_of::<usize>()) };
+        let address = self.address.load(Ordering::Acquire);
+        (address != 0).then(|| unsafe { std::mem::transmute_copy(&address) })
+    }
+
+    /// Address of `detour`, which [`hook!`] passes so that it must have type `F`
+    #[allow(dead_code)]
+    pub fn detour_address(&self, detour: F) -> usize {
+        const { assert!(size_of::<F>() == size_of::<usize>()) };
+        unsafe { std::mem::transmute_copy(&detour) }
+    }
+}
+
+/// Type-erased access to an [`Original`] for the registry
+pub trait Trampoline: Sync {
+    fn set(&self, address: usize);
+}
+
+impl<F: Sync> Trampoline for Original<F> {
+    fn set(&self, address: usize) {
+        self.address.store(address, Ordering::Release);
+    }
+}
+
+/// A hook of a game function found by signature
+pub struct HookDef {
+    pub signature: &'static Signature,
+    pub enabled: bool,
+    /// Address of the detour, as a function since it cannot be computed in a static
+    detour: fn() -> usize,
+    original: &'static dyn Trampoline,
+}
+
+impl HookDef {
+    pub fn name(&self) -> &'static str {
+        self.signature.name
+    }
+}
+
+/// Declare a [`HookDef`] from a signature, a detour function and its [`Original`] static
+///
+/// Append `, enabled: false` to declare a hook that is not installed.
+#[allow(unused_macros)]
+macro_rules! hook {
+    ($signature:expr, $detour:path, $original:path) => {
+        hook!($signature, $detour, $original, enabled: true)
+    };
+    ($signature:expr, $detour:path, $original:path, enabled: $enabled:expr) => {
+        HookDef {
+            signature: &$signature,
+            enabled: $enabled,
+            detour: || $original.detour_address($detour),
+            original: &$original,
+        }
+    };
+}
+
+/// All hooks, in the order they are installed
+pub static HOOKS: &[HookDef] = &[];
+
+/// Signatures of every hook, in [`HOOKS`] order
+pub fn signatures() -> Vec<&'static Signature> {
+    HOOKS.iter().map(|hook| hook.signature).collect()
+}
+
+/// Where a hook stands
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HookStatus {
+    /// Turned off in [`HOOKS`]
+    Off,
+    /// The signature did not resolve
+    NotFound(ScanError),
+    /// MinHook could not create or enable the hook
+    Failed(MH_STATUS),
+    /// Installed at `target` and running
+    Enabled(usize),
+    /// Installed at `target` but bypassed
+    Disabled(usize),
+    /// Removed on unload
+    Removed,
+}
+
+impl std::fmt::Display for HookStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HookStatus::Off => write!(f, "off"),
+            HookStatus::NotFound(e) => write!(f, "not found: {}", e),
+            HookStatus::Failed(status) => write!(f, "failed: {:?}", status),
+            HookStatus::Enabled(target) => write!(f, "enabled at 0x{:08X}", target),
+            HookStatus::Disabled(target) => write!(f, "disabled at 0x{:08X}", target),
+            HookStatus::Removed => write!(f, "removed"),
+        }
+    }
 }
 
-impl HookAddresses {
-    /// Scan for all hook target signatures
-    pub fn scan(_base: usize) -> Self {
-        // No hooks currently active
-        Self {}
+/// Reasons a hook could not be switched on or off
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookError {
+    /// No hook has this name
+    Unknown,
+    /// The hook is not installed
+    NotInstalled,
+    /// MinHook refused
+    MinHook(MH_STATUS),
+}
+
+impl std::fmt::Display for HookError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HookError::Unknown => write!(f, "no such hook"),
+            HookError::NotInstalled => write!(f, "hook is not installed"),
+            HookError::MinHook(status) => write!(f, "MinHook error {:?}", status),
+        }
+    }
+}
+
+/// Status of every hook, in [`HOOKS`] order
+static STATUS: Mutex<Vec<HookStatus>> = Mutex::new(Vec::new());
+
+/// Install every enabled hook whose signature resolved
+///
+/// `resolved` holds the scan results for [`signatures`], in the same order.
+pub fn install_hooks(resolved: &[Result<Match, ScanError>]) {
+    let mut status = STATUS.lock().unwrap_or_else(|e| e.into_inner());
+
+    *status = HOOKS
+        .iter()
+        .zip(resolved)
+        .map(|(hook, result)| {
+            let state = match result {
+                _ if !hook.enabled => HookStatus::Off,
+                Err(e) => HookStatus::NotFound(*e),
+                Ok(m) => install(hook, m.address),
+            };
+
+            #[cfg(debug_assertions)]
+            println!("hooks: {}: {}", hook.name(), state);
+
+            state
+        })
+        .collect();
+}
+
+/// Create and enable the hook at `target`
+fn install(hook: &HookDef, target: usize) -> HookStatus {
+    let created =
+        unsafe { MinHook::create_hook(target as *mut c_void, (hook.detour)() as *mut c_void) };
+    let trampoline = match created {
+        Ok(trampoline) => trampoline,
+        Err(e) => return HookStatus::Failed(e),
+    };
+    hook.original.set(trampoline as usize);
+
+    match unsafe { MinHook::enable_hook(target as *mut c_void) } {
+        Ok(()) => HookStatus::Enabled(target),
+        Err(e) => {
+            let _ = unsafe { MinHook::remove_hook(target as *mut c_void) };
+            hook.original.set(0);
+            HookStatus::Failed(e)
+        }
     }
 }
 
-/// Install all function hooks
-pub fn install_hooks(_addrs: &HookAddresses) -> Result<(), MH_STATUS> {
-    // No hooks currently active - infrastructure ready for future use
+/// Switch an installed hook on or off at runtime
+///
+/// A disabled hook leaves the original function running untouched; its
+/// [`Original`] stays valid.
+#[allow(dead_code)]
+pub fn set_enabled(name: &str, enabled: bool) -> Result<(), HookError> {
+    let mut status = STATUS.lock().unwrap_or_else(|e| e.into_inner());
+    let index = HOOKS
+        .iter()
+        .position(|hook| hook.name() == name)
+        .ok_or(HookError::Unknown)?;
+    let state = status.get_mut(index).ok_or(HookError::NotInstalled)?;
+
+    let target = match *state {
+        HookStatus::Enabled(target) | HookStatus::Disabled(target) => target,
+        _ => return Err(HookError::NotInstalled),
+    };
+
+    let result = if enabled {
+        unsafe { MinHook::enable_hook(target as *mut c_void) }
+    } else {
+        unsafe { MinHook::disable_hook(target as *mut c_void) }
+    };
+    result.map_err(HookError::MinHook)?;
+
+    *state = if enabled {
+        HookStatus::Enabled(target)
+    } else {
+        HookStatus::Disabled(target)
+    };
 
     #[cfg(debug_assertions)]
-    println!("hooks: No hooks to install (infrastructure ready)");
+    println!("hooks: {}: {}", name, state);
 
     Ok(())
 }
 
-/// Cleanup hooks on unload
+/// Name and status of every hook, in [`HOOKS`] order
 #[allow(dead_code)]
+pub fn status() -> Vec<(&'static str, HookStatus)> {
+    let status = STATUS.lock().unwrap_or_else(|e| e.into_inner());
+
+    HOOKS
+        .iter()
+        .enumerate()
+        .map(|(i, hook)| {
+            let state = status.get(i).cloned().unwrap_or(HookStatus::Off);
+            (hook.name(), state)
+        })
+        .collect()
+}
+
+/// Remove every installed hook on unload
+///
+/// Each [`Original`] is cleared once its hook is gone, since MinHook frees the
+/// trampoline.
 pub fn remove_hooks() {
     #[cfg(debug_assertions)]
     println!("hooks: Removing all hooks");
 
-    unsafe {
-        let _ = MinHook::disable_all_hooks();
+    let mut status = STATUS.lock().unwrap_or_else(|e| e.into_inner());
+
+    for (hook, state) in HOOKS.iter().zip(status.iter_mut()) {
+        let (HookStatus::Enabled(target) | HookStatus::Disabled(target)) = *state else {
+            continue;
+        };
+
+        match unsafe { MinHook::remove_hook(target as *mut c_void) } {
+            Ok(()) => {
+                hook.original.set(0);
+                *state = HookStatus::Removed;
+            }
+            Err(_e) => {
+                #[cfg(debug_assertions)]
+                println!("hooks: Failed to remove {}: {:?}", hook.name(), _e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static ORIGINAL: Original<extern "C" fn(u32) -> u32> = Original::new();
+
+    extern "C" fn detour(value: u32) -> u32 {
+        ORIGINAL.get().map_or(0, |original| original(value) + 1)
+    }
+
+    extern "C" fn original(value: u32) -> u32 {
+        value * 2
+    }
+
+    #[test]
+    fn hook_macro_declares_a_hook() {
+        let hooks = [
+            hook!(signatures::INIT_OPTIONS, detour, ORIGINAL),
+            hook!(signatures::CREATE_SLIDER, detour, ORIGINAL, enabled: false),
+        ];
+
+        assert_eq!(hooks[0].name(), "InitOptions");
+        assert!(hooks[0].enabled);
+        assert_eq!(hooks[1].name(), "CreateSliderOption");
+        assert!(!hooks[1].enabled);
+        assert_eq!(
+            (hooks[0].detour)(),
+            detour as extern "C" fn(u32) -> u32 as usize
+        );
+
+        // The trampoline reaches the detour through its typed static
+        assert_eq!(detour(20), 0);
+        hooks[0]
+            .original
+            .set(original as extern "C" fn(u32) -> u32 as usize);
+        assert_eq!(ORIGINAL.get().map(|f| f(20)), Some(40));
+        assert_eq!(detour(20), 41);
+        hooks[0].original.set(0);
+        assert!(ORIGINAL.get().is_none());
     }
 }
@@ -0,0 +1,70 @@
+//! Import address table hooks
+//!
+//! Calls from a module to another DLL go through its IAT, one pointer per
+//! imported function. Swapping that pointer redirects every call the module
+//! makes to the function, without touching code and without affecting other
+//! modules. This is the least invasive way to intercept Win32 calls such as
+//! `CreateWindowExA` or `RegQueryValueExA` made by Dunia.dll.
+//!
+//! The swap is a [`Patch`], so it is undone with the other patches on unload.
+
+use crate::patches::PatchWrite;
+use crate::patches::memory::{self, Patch, PatchError, Threads};
+use crate::patches::pe::Module;
+
+/// Reasons an import could not be hooked
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IatError {
+    /// The module does not import the function by name
+    NotImported,
+    /// The slot could not be written
+    Patch(PatchError),
+}
+
+impl std::fmt::Display for IatError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IatError::NotImported => write!(f, "function is not imported"),
+            IatError::Patch(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Redirect `module`'s calls to `dll!function` to `detour`, returning the original
+///
+/// The returned pointer is whatever the slot held, normally the function in
+/// `dll`; call it to forward to the real implementation.
+///
+/// # Safety
+/// `F` must be the function pointer type of the import, with its calling
+/// convention (`extern "system"` for Win32).
+#[allow(dead_code)]
+pub unsafe fn hook<F: Copy>(
+    module: &Module,
+    name: &'static str,
+    dll: &str,
+    function: &str,
+    detour: F,
+) -> Result<F, IatError> {
+    let slot = module
+        .import_slot(dll, function)
+        .ok_or(IatError::NotImported)?;
+    let current = module
+        .read(slot, size_of::<usize>())
+        .ok_or(IatError::NotImported)?;
+
+    let detour = unsafe { memory::fn_address(detour) };
+    let write = PatchWrite::exact(slot, current, &detour.to_ne_bytes());
+    let original = unsafe { memory::fn_from_bytes(current) };
+
+    memory::apply(Patch::new(name, vec![write]).with_threads(Threads::Suspended))
+        .map_err(IatError::Patch)?;
+
+    #[cfg(debug_assertions)]
+    println!(
+        "patches: Hooked import {}!{} at 0x{:08X} -> 0x{:08X}",
+        dll, function, slot, detour
+    );
+
+    Ok(original)
+}
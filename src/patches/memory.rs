@@ -1,71 +1,634 @@
 //! Memory patching utilities
+//!
+//! Raw writes go through [`write_bytes`]. Patches to the game go through
+//! [`Patch`], which remembers the bytes it replaced so it can be reverted, and
+//! [`apply`], which keeps a registry of applied patches so that two patches
+//! never write the same bytes and everything can be undone on unload.
 
+use crate::patches::PatchWrite;
 use std::ffi::c_void;
+use std::sync::Mutex;
+use windows::Win32::Foundation::{CloseHandle, HANDLE};
+use windows::Win32::System::Diagnostics::Debug::{
+    CONTEXT, CONTEXT_CONTROL_X86, FlushInstructionCache, GetThreadContext,
+};
+use windows::Win32::System::Diagnostics::ToolHelp::{
+    CreateToolhelp32Snapshot, TH32CS_SNAPTHREAD, THREADENTRY32, Thread32First, Thread32Next,
+};
 use windows::Win32::System::Memory::{
-    PAGE_EXECUTE_READWRITE, PAGE_PROTECTION_FLAGS, VirtualProtect,
+    MEMORY_BASIC_INFORMATION, PAGE_EXECUTE, PAGE_EXECUTE_READ, PAGE_EXECUTE_READWRITE,
+    PAGE_EXECUTE_WRITECOPY, PAGE_PROTECTION_FLAGS, PAGE_READWRITE, PAGE_WRITECOPY, VirtualProtect,
+    VirtualQuery,
+};
+use windows::Win32::System::Threading::{
+    GetCurrentProcess, GetCurrentProcessId, GetCurrentThreadId, OpenThread, ResumeThread,
+    SuspendThread, THREAD_GET_CONTEXT, THREAD_SUSPEND_RESUME,
 };
 
+/// Reasons memory could not be patched
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatchError {
+    /// VirtualQuery could not describe the memory (it is probably not mapped)
+    Query(usize),
+    /// VirtualProtect refused to make the memory writable
+    Protect(usize),
+    /// The other threads of the process could not be enumerated
+    Threads,
+    /// A rel32 branch at `from` cannot reach `to`
+    OutOfRange { from: usize, to: usize },
+    /// The patch writes bytes already written by another patch (or itself)
+    Overlap { address: usize, with: &'static str },
+    /// The patch is not applied, so there is nothing to revert
+    NotApplied,
+    /// A suspended thread stopped inside the bytes to be rewritten, at this address
+    Executing(usize),
+}
+
+impl std::fmt::Display for PatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PatchError::Query(address) => write!(f, "0x{:08X} is not mapped", address),
+            PatchError::Protect(address) => {
+                write!(f, "cannot make 0x{:08X} writable", address)
+            }
+            PatchError::Threads => write!(f, "cannot enumerate threads"),
+            PatchError::OutOfRange { from, to } => {
+                write!(f, "0x{:08X} cannot reach 0x{:08X} with rel32", from, to)
+            }
+            PatchError::Overlap { address, with } => {
+                write!(f, "0x{:08X} is already patched by {}", address, with)
+            }
+            PatchError::NotApplied => write!(f, "patch is not applied"),
+            PatchError::Executing(address) => {
+                write!(
+                    f,
+                    "a thread is executing 0x{:08X}, inside the write",
+                    address
+                )
+            }
+        }
+    }
+}
+
+/// Whether other threads keep running while code is rewritten
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Threads {
+    /// Write while other threads run; fine for single-byte writes and data
+    Running,
+    /// Suspend every other thread for the duration of the write, so none of
+    /// them can execute a half-written multi-byte instruction
+    Suspended,
+}
+
 /// Write bytes to a memory address, handling page protection
-pub fn write_bytes(address: usize, bytes: &[u8]) -> bool {
-    if bytes.is_empty() {
-        return true;
+///
+/// Pages are only made writable if they are not already: code pages become
+/// RWX for the duration of the write, data pages RW. The instruction cache is
+/// flushed after writing to executable memory.
+pub fn write_bytes(address: usize, bytes: &[u8]) -> Result<(), PatchError> {
+    write_unlogged(address, bytes)?;
+
+    #[cfg(debug_assertions)]
+    println!("patches: Wrote {} bytes to 0x{:08X}", bytes.len(), address);
+
+    Ok(())
+}
+
+/// Write code, optionally with every other thread suspended
+///
+/// Suspension only happens for writes longer than one byte, since a single
+/// byte is always replaced atomically. With threads suspended, the write is
+/// refused if any of them stopped inside it.
+#[allow(dead_code)]
+pub fn write_code(address: usize, bytes: &[u8], threads: Threads) -> Result<(), PatchError> {
+    if threads == Threads::Suspended && bytes.len() > 1 {
+        with_threads_suspended(|stopped_at| {
+            if let Some(&at) = stopped_at
+                .iter()
+                .find(|&&at| inside(address, bytes.len(), at))
+            {
+                return Err(PatchError::Executing(at));
+            }
+            write_unlogged(address, bytes)
+        })??;
+    } else {
+        write_unlogged(address, bytes)?;
     }
 
+    #[cfg(debug_assertions)]
+    println!("patches: Wrote {} bytes to 0x{:08X}", bytes.len(), address);
+
+    Ok(())
+}
+
+/// [`write_bytes`] without logging, safe to call while other threads are suspended
+///
+/// Does not allocate or take any lock another thread could be holding.
+fn write_unlogged(address: usize, bytes: &[u8]) -> Result<(), PatchError> {
+    let mut written = 0;
+
+    // Protection is per region, so a write spanning regions is split
+    while written < bytes.len() {
+        let start = address + written;
+        let mut info = MEMORY_BASIC_INFORMATION::default();
+        let size = std::mem::size_of::<MEMORY_BASIC_INFORMATION>();
+        if unsafe { VirtualQuery(Some(start as *const c_void), &mut info, size) } == 0 {
+            return Err(PatchError::Query(start));
+        }
+
+        let region_end = info.BaseAddress as usize + info.RegionSize;
+        let len = (bytes.len() - written).min(region_end - start);
+        write_region(start, &bytes[written..written + len], info.Protect)?;
+        written += len;
+    }
+
+    Ok(())
+}
+
+/// Write within a single region whose current protection is `protect`
+fn write_region(
+    address: usize,
+    bytes: &[u8],
+    protect: PAGE_PROTECTION_FLAGS,
+) -> Result<(), PatchError> {
     let ptr = address as *mut u8;
     let len = bytes.len();
+
+    // Ignore PAGE_GUARD / PAGE_NOCACHE / PAGE_WRITECOMBINE
+    let access = PAGE_PROTECTION_FLAGS(protect.0 & 0xFF);
+    let executable = matches!(
+        access,
+        PAGE_EXECUTE | PAGE_EXECUTE_READ | PAGE_EXECUTE_READWRITE | PAGE_EXECUTE_WRITECOPY
+    );
+    let writable = matches!(
+        access,
+        PAGE_READWRITE | PAGE_WRITECOPY | PAGE_EXECUTE_READWRITE | PAGE_EXECUTE_WRITECOPY
+    );
+
     let mut old_protect = PAGE_PROTECTION_FLAGS(0);
 
     unsafe {
         // Make memory writable
-        let protect_result = VirtualProtect(
-            ptr as *const c_void,
-            len,
-            PAGE_EXECUTE_READWRITE,
-            &mut old_protect,
-        );
+        if !writable {
+            let needed = if executable {
+                PAGE_EXECUTE_READWRITE
+            } else {
+                PAGE_READWRITE
+            };
 
-        if protect_result.is_err() {
-            #[cfg(debug_assertions)]
-            println!("patches: VirtualProtect failed for 0x{:08X}", address);
-            return false;
+            if VirtualProtect(ptr as *const c_void, len, needed, &mut old_protect).is_err() {
+                return Err(PatchError::Protect(address));
+            }
         }
 
         // Write the bytes
         std::ptr::copy_nonoverlapping(bytes.as_ptr(), ptr, len);
 
         // Restore original protection
-        let _ = VirtualProtect(ptr as *const c_void, len, old_protect, &mut old_protect);
+        if !writable {
+            let _ = VirtualProtect(ptr as *const c_void, len, old_protect, &mut old_protect);
+        }
+
+        if executable {
+            let _ = FlushInstructionCache(GetCurrentProcess(), Some(ptr as *const c_void), len);
+        }
     }
 
-    #[cfg(debug_assertions)]
-    println!("patches: Wrote {} bytes to 0x{:08X}", len, address);
+    Ok(())
+}
+
+/// Run `f` with every other thread of the process suspended
+///
+/// SuspendThread only asks for a thread to stop, so each thread's context is
+/// read before `f` runs: GetThreadContext waits until the thread has actually
+/// stopped. `f` gets the instruction pointer of every thread whose context
+/// could be read.
+///
+/// `f` must not allocate, print or take locks: a suspended thread may be
+/// holding the heap or stdout lock, and waiting on it would deadlock.
+pub fn with_threads_suspended<R>(f: impl FnOnce(&[usize]) -> R) -> Result<R, PatchError> {
+    let threads = other_threads()?;
+    let mut stopped_at = Vec::with_capacity(threads.len());
+
+    for &thread in &threads {
+        let mut context = CONTEXT {
+            ContextFlags: CONTEXT_CONTROL_X86,
+            ..Default::default()
+        };
+        unsafe {
+            SuspendThread(thread);
+            if GetThreadContext(thread, &mut context).is_ok() {
+                stopped_at.push(context.Eip as usize);
+            }
+        }
+    }
+
+    let result = f(&stopped_at);
+
+    for &thread in &threads {
+        unsafe {
+            ResumeThread(thread);
+            let _ = CloseHandle(thread);
+        }
+    }
+
+    Ok(result)
+}
+
+/// Handles to every thread of this process but the current one, opened to
+/// suspend them and read their context
+fn other_threads() -> Result<Vec<HANDLE>, PatchError> {
+    let snapshot = unsafe { CreateToolhelp32Snapshot(TH32CS_SNAPTHREAD, 0) }
+        .map_err(|_| PatchError::Threads)?;
+
+    let process = unsafe { GetCurrentProcessId() };
+    let current = unsafe { GetCurrentThreadId() };
+
+    let mut threads = Vec::new();
+    let mut entry = THREADENTRY32 {
+        dwSize: std::mem::size_of::<THREADENTRY32>() as u32,
+        ..Default::default()
+    };
+
+    let mut more = unsafe { Thread32First(snapshot, &mut entry) }.is_ok();
+    while more {
+        if entry.th32OwnerProcessID == process && entry.th32ThreadID != current {
+            // Threads that exit in the meantime simply fail to open
+            if let Ok(thread) = unsafe {
+                OpenThread(
+                    THREAD_SUSPEND_RESUME | THREAD_GET_CONTEXT,
+                    false,
+                    entry.th32ThreadID,
+                )
+            } {
+                threads.push(thread);
+            }
+        }
+        more = unsafe { Thread32Next(snapshot, &mut entry) }.is_ok();
+    }
 
-    true
+    let _ = unsafe { CloseHandle(snapshot) };
+    Ok(threads)
+}
+
+/// Displacement for a rel32 operand that reaches `to` from `next`, the address
+/// of the instruction following the branch
+///
+/// In 32-bit code EIP wraps around, so every address is reachable; the range
+/// only matters when the distance is computed for a 64-bit process.
+pub fn rel32(next: usize, to: usize) -> Result<i32, PatchError> {
+    let displacement = to as i64 - next as i64;
+    match i32::try_from(displacement) {
+        Ok(displacement) => Ok(displacement),
+        Err(_) if cfg!(target_pointer_width = "32") => Ok(displacement as i32),
+        Err(_) => Err(PatchError::OutOfRange {
+            from: next.wrapping_sub(5),
+            to,
+        }),
+    }
 }
 
 /// Write a relative call instruction (E8 xx xx xx xx)
 #[allow(dead_code)]
-pub fn write_call(from: usize, to: usize) -> bool {
-    let relative = (to as isize) - (from as isize) - 5;
+pub fn write_call(from: usize, to: usize) -> Result<(), PatchError> {
+    let relative = rel32(from + 5, to)?;
     let mut bytes = [0u8; 5];
     bytes[0] = 0xE8; // CALL opcode
-    bytes[1..5].copy_from_slice(&(relative as i32).to_le_bytes());
+    bytes[1..5].copy_from_slice(&relative.to_le_bytes());
     write_bytes(from, &bytes)
 }
 
 /// Write a relative jump instruction (E9 xx xx xx xx)
 #[allow(dead_code)]
-pub fn write_jump(from: usize, to: usize) -> bool {
-    let relative = (to as isize) - (from as isize) - 5;
+pub fn write_jump(from: usize, to: usize) -> Result<(), PatchError> {
+    let relative = rel32(from + 5, to)?;
     let mut bytes = [0u8; 5];
     bytes[0] = 0xE9; // JMP opcode
-    bytes[1..5].copy_from_slice(&(relative as i32).to_le_bytes());
+    bytes[1..5].copy_from_slice(&relative.to_le_bytes());
     write_bytes(from, &bytes)
 }
 
 /// Write NOP instructions
 #[allow(dead_code)]
-pub fn write_nops(address: usize, count: usize) -> bool {
+pub fn write_nops(address: usize, count: usize) -> Result<(), PatchError> {
     let nops = vec![0x90u8; count];
     write_bytes(address, &nops)
 }
+
+/// Address of the function pointer `f`
+///
+/// # Safety
+/// `F` must be a function pointer type.
+#[allow(dead_code)]
+pub unsafe fn fn_address<F: Copy>(f: F) -> usize {
+    const { assert!(size_of::<F>() == size_of::<usize>()) };
+    unsafe { std::mem::transmute_copy(&f) }
+}
+
+/// The function pointer stored in `bytes`, as read from a pointer-sized slot
+///
+/// # Safety
+/// `F` must be a function pointer type matching the function in the slot.
+#[allow(dead_code)]
+pub unsafe fn fn_from_bytes<F: Copy>(bytes: &[u8]) -> F {
+    const { assert!(size_of::<F>() == size_of::<usize>()) };
+    let mut address = [0; size_of::<usize>()];
+    address.copy_from_slice(bytes);
+    unsafe { std::mem::transmute_copy(&usize::from_ne_bytes(address)) }
+}
+
+/// A set of writes that is applied and reverted as a unit
+pub struct Patch {
+    name: &'static str,
+    writes: Vec<PatchWrite>,
+    threads: Threads,
+    /// Bytes replaced by each write, recorded when the patch is applied
+    original: Option<Vec<Vec<u8>>>,
+}
+
+impl Patch {
+    pub fn new(name: &'static str, writes: Vec<PatchWrite>) -> Self {
+        Self {
+            name,
+            writes,
+            threads: Threads::Running,
+            original: None,
+        }
+    }
+
+    /// Whether other threads are suspended while the patch is applied or reverted
+    ///
+    /// As with [`write_code`], suspension only happens if a write is longer
+    /// than one byte.
+    pub fn with_threads(self, threads: Threads) -> Self {
+        Self { threads, ..self }
+    }
+
+    /// First address written by both this patch and `other`
+    fn overlap(&self, other: &Patch) -> Option<usize> {
+        self.writes.iter().find_map(|write| {
+            other
+                .writes
+                .iter()
+                .find_map(|theirs| first_shared(write, theirs))
+        })
+    }
+
+    /// First of `stopped_at` that lies past the first byte of a write
+    ///
+    /// A thread stopped at the first byte runs the new instruction in full,
+    /// but one stopped further in would resume in the middle of it.
+    fn executing_inside(&self, stopped_at: &[usize]) -> Option<usize> {
+        stopped_at.iter().copied().find(|&at| {
+            self.writes
+                .iter()
+                .any(|write| inside(write.address, write.bytes.len(), at))
+        })
+    }
+
+    /// First address written twice by this patch
+    fn self_overlap(&self) -> Option<usize> {
+        self.writes.iter().enumerate().find_map(|(i, write)| {
+            self.writes[i + 1..]
+                .iter()
+                .find_map(|other| first_shared(write, other))
+        })
+    }
+
+    /// Write every byte of the patch, or none of them
+    ///
+    /// The replaced bytes are recorded first. If any write fails, the writes
+    /// already made are undone before returning the error.
+    pub fn apply(&mut self) -> Result<(), PatchError> {
+        if let Some(address) = self.self_overlap() {
+            return Err(PatchError::Overlap {
+                address,
+                with: self.name,
+            });
+        }
+
+        let original: Vec<Vec<u8>> = self
+            .writes
+            .iter()
+            .map(|write| unsafe {
+                std::slice::from_raw_parts(write.address as *const u8, write.bytes.len()).to_vec()
+            })
+            .collect();
+
+        let patched: Vec<&[u8]> = self.writes.iter().map(|write| &write.bytes[..]).collect();
+        let restore: Vec<&[u8]> = original.iter().map(|bytes| &bytes[..]).collect();
+        self.write_each(&patched, &restore)?;
+
+        self.original = Some(original);
+        Ok(())
+    }
+
+    /// Restore the bytes the patch replaced
+    pub fn revert(&mut self) -> Result<(), PatchError> {
+        let original = self.original.as_ref().ok_or(PatchError::NotApplied)?;
+
+        let restore: Vec<&[u8]> = original.iter().map(|bytes| &bytes[..]).collect();
+        let patched: Vec<&[u8]> = self.writes.iter().map(|write| &write.bytes[..]).collect();
+        self.write_each(&restore, &patched)?;
+
+        self.original = None;
+        Ok(())
+    }
+
+    /// Write `contents[i]` at the address of write `i`, for every write
+    ///
+    /// If a write fails, the writes already made are rolled back to `rollback`.
+    /// With threads suspended, nothing is written if any of them stopped
+    /// inside a write.
+    fn write_each(&self, contents: &[&[u8]], rollback: &[&[u8]]) -> Result<(), PatchError> {
+        // Runs with other threads possibly suspended, so nothing in here allocates
+        let write = |stopped_at: &[usize]| {
+            if let Some(at) = self.executing_inside(stopped_at) {
+                return Err(PatchError::Executing(at));
+            }
+            for (i, write) in self.writes.iter().enumerate() {
+                if let Err(e) = write_unlogged(write.address, contents[i]) {
+                    for (done, bytes) in self.writes[..i].iter().zip(rollback).rev() {
+                        let _ = write_unlogged(done.address, bytes);
+                    }
+                    return Err(e);
+                }
+            }
+            Ok(())
+        };
+
+        let multi_byte = self.writes.iter().any(|write| write.bytes.len() > 1);
+        if self.threads == Threads::Suspended && multi_byte {
+            with_threads_suspended(write)??;
+        } else {
+            write(&[])?;
+        }
+
+        #[cfg(debug_assertions)]
+        for (write, bytes) in self.writes.iter().zip(contents) {
+            println!(
+                "patches: Wrote {} bytes to 0x{:08X}",
+                bytes.len(),
+                write.address
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// Whether `at` lies in `address..address + len` but is not `address` itself
+fn inside(address: usize, len: usize, at: usize) -> bool {
+    at > address && at < address + len
+}
+
+/// First address written by both `a` and `b`
+fn first_shared(a: &PatchWrite, b: &PatchWrite) -> Option<usize> {
+    let start = a.address.max(b.address);
+    let end = (a.address + a.bytes.len()).min(b.address + b.bytes.len());
+    (start < end).then_some(start)
+}
+
+/// Patches currently applied, in the order they were applied
+static APPLIED: Mutex<Vec<Patch>> = Mutex::new(Vec::new());
+
+/// Apply a patch and register it for [`revert_all`]
+///
+/// Refuses to apply a patch that writes any byte already written by an
+/// applied patch.
+pub fn apply(mut patch: Patch) -> Result<(), PatchError> {
+    let mut applied = APPLIED.lock().unwrap_or_else(|e| e.into_inner());
+
+    if let Some((address, with)) = applied
+        .iter()
+        .find_map(|other| Some((patch.overlap(other)?, other.name)))
+    {
+        return Err(PatchError::Overlap { address, with });
+    }
+
+    patch.apply()?;
+    applied.push(patch);
+    Ok(())
+}
+
+/// Revert the registered patch called `name` and forget it
+///
+/// Other patches stay applied; since no two patches share a byte, the order
+/// they are reverted in does not matter.
+#[allow(dead_code)]
+pub fn revert(name: &str) -> Result<(), PatchError> {
+    let mut applied = APPLIED.lock().unwrap_or_else(|e| e.into_inner());
+    let index = applied
+        .iter()
+        .position(|patch| patch.name == name)
+        .ok_or(PatchError::NotApplied)?;
+
+    applied[index].revert()?;
+    applied.remove(index);
+
+    #[cfg(debug_assertions)]
+    println!("patches: Reverted {}", name);
+
+    Ok(())
+}
+
+/// Revert every registered patch, most recent first
+///
+/// A patch that fails to revert stays registered along with the bytes it
+/// replaced, so it can be retried or reported. Returns true if none is left.
+pub fn revert_all() -> bool {
+    let mut applied = APPLIED.lock().unwrap_or_else(|e| e.into_inner());
+    let mut failed = Vec::new();
+
+    while let Some(mut patch) = applied.pop() {
+        match patch.revert() {
+            Ok(()) => {
+                #[cfg(debug_assertions)]
+                println!("patches: Reverted {}", patch.name);
+            }
+            Err(_e) => {
+                #[cfg(debug_assertions)]
+                println!("patches: Failed to revert {}: {}", patch.name, _e);
+
+                failed.push(patch);
+            }
+        }
+    }
+
+    // Back in the order they were applied
+    failed.reverse();
+    *applied = failed;
+    applied.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(address: usize, len: usize) -> PatchWrite {
+        PatchWrite::exact(address, &vec![0; len], &vec![0x90; len])
+    }
+
+    #[test]
+    fn first_shared_is_the_start_of_the_intersection() {
+        assert_eq!(
+            first_shared(&write(0x100, 4), &write(0x102, 4)),
+            Some(0x102)
+        );
+        assert_eq!(
+            first_shared(&write(0x102, 4), &write(0x100, 4)),
+            Some(0x102)
+        );
+        assert_eq!(
+            first_shared(&write(0x100, 8), &write(0x102, 1)),
+            Some(0x102)
+        );
+        // Adjacent writes do not share a byte
+        assert_eq!(first_shared(&write(0x100, 4), &write(0x104, 4)), None);
+        assert_eq!(first_shared(&write(0x104, 1), &write(0x100, 4)), None);
+    }
+
+    #[test]
+    fn overlapping_patches() {
+        let a = Patch::new("A", vec![write(0x100, 2), write(0x200, 4)]);
+        let b = Patch::new("B", vec![write(0x180, 1), write(0x203, 2)]);
+        let c = Patch::new("C", vec![write(0x102, 2), write(0x1FC, 4)]);
+
+        assert_eq!(a.overlap(&b), Some(0x203));
+        assert_eq!(b.overlap(&a), Some(0x203));
+        assert_eq!(a.overlap(&c), None);
+        assert_eq!(a.self_overlap(), None);
+
+        let twice = Patch::new(
+            "Twice",
+            vec![write(0x100, 1), write(0x200, 2), write(0x201, 1)],
+        );
+        assert_eq!(twice.self_overlap(), Some(0x201));
+    }
+
+    #[test]
+    fn threads_stopped_inside_a_write() {
+        let patch = Patch::new("A", vec![write(0x100, 5), write(0x200, 1)]);
+
+        // At the first byte, or anywhere outside, the thread runs whole instructions
+        assert_eq!(patch.executing_inside(&[]), None);
+        assert_eq!(patch.executing_inside(&[0x100, 0x105, 0x200, 0x201]), None);
+        assert_eq!(patch.executing_inside(&[0x0FF, 0x101]), Some(0x101));
+        assert_eq!(patch.executing_inside(&[0x104, 0x102]), Some(0x104));
+    }
+
+    #[test]
+    fn failed_reverts_stay_registered() {
+        // Never applied, so it has no original bytes and cannot be reverted
+        let stuck = Patch::new("Stuck", vec![write(0x100, 1)]);
+        APPLIED.lock().unwrap().push(stuck);
+
+        assert!(!revert_all());
+        assert_eq!(APPLIED.lock().unwrap().len(), 1);
+        assert!(!revert_all(), "retried and still failing");
+
+        let stuck = APPLIED.lock().unwrap().pop().unwrap();
+        assert_eq!(stuck.name, "Stuck");
+        assert!(revert_all());
+    }
+}
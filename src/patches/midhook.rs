@@ -0,0 +1,256 @@
+//! Hooks in the middle of a function
+//!
+//! MinHook only detours whole functions. A mid-hook instead runs a Rust
+//! closure at an arbitrary instruction: the instructions at the site are
+//! replaced by a `jmp` to a stub in a code cave, which saves every register,
+//! calls the closure with them and then runs the displaced instructions before
+//! jumping back.
+//!
+//! ```text
+//! pushfd
+//! pushad
+//! add [esp+0Ch], 4        ; pushad saved esp after pushfd: make it the site's
+//! cld                     ; the closure may assume the direction flag is clear
+//! push esp                ; &mut Context
+//! push callback
+//! call dispatch
+//! add esp, 8
+//! popad                   ; registers as the closure left them
+//! popfd
+//! <displaced instructions, relocated>
+//! jmp site + displaced
+//! ```
+//!
+//! The jump at the site is a [`Patch`] like any other, so it is reverted with
+//! the rest on unload.
+
+use crate::patches::PatchWrite;
+use crate::patches::cave::{self, CaveError};
+use crate::patches::memory::{self, Patch, PatchError, Threads};
+use crate::patches::pe::Module;
+use crate::patches::x86::{self, DecodeError};
+use std::sync::Mutex;
+
+/// Length of the `jmp rel32` written at the site
+const JMP_LEN: usize = 5;
+
+/// Registers at the hooked instruction, as pushed by `pushfd; pushad`
+///
+/// Changes to any field but `esp` are written back before the displaced
+/// instructions run. `esp` holds the stack pointer at the site (the stub
+/// corrects the value `pushad` saved, which is 4 lower because of `pushfd`)
+/// and is read-only: `popad` skips it.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Context {
+    pub edi: u32,
+    pub esi: u32,
+    pub ebp: u32,
+    pub esp: u32,
+    pub ebx: u32,
+    pub edx: u32,
+    pub ecx: u32,
+    pub eax: u32,
+    pub eflags: u32,
+}
+
+impl Context {
+    /// Address of the `index`th dword on the stack at the site (`[esp + index * 4]`)
+    #[allow(dead_code)]
+    pub fn stack(&self, index: usize) -> *mut u32 {
+        (self.esp as usize + index * 4) as *mut u32
+    }
+}
+
+type Callback = Box<dyn Fn(&mut Context) + Send + Sync>;
+type Dispatch = extern "C" fn(*const Callback, *mut Context);
+
+/// Reasons a mid-hook could not be installed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MidHookError {
+    /// The instructions at the site could not be decoded or relocated
+    Decode(DecodeError),
+    /// No code cave is available for the stub
+    Cave(CaveError),
+    /// The stub or the jump to it could not be written
+    Patch(PatchError),
+}
+
+impl std::fmt::Display for MidHookError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MidHookError::Decode(e) => write!(f, "{}", e),
+            MidHookError::Cave(e) => write!(f, "{}", e),
+            MidHookError::Patch(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Closures of every installed mid-hook
+///
+/// Stubs point at the inner boxes, which must not move when the list grows.
+#[allow(clippy::vec_box)]
+static CALLBACKS: Mutex<Vec<Box<Callback>>> = Mutex::new(Vec::new());
+
+/// Run `callback` whenever the game reaches the instruction at `address`
+///
+/// Whole instructions covering at least five bytes are displaced. None of them
+/// may be the target of a branch from elsewhere, since it would land in the
+/// middle of the `jmp`; pick sites a few instructions past any label.
+///
+/// Other threads are suspended while the jump is written. If one of them
+/// stopped inside the displaced instructions, nothing is written and
+/// [`PatchError::Executing`] is returned; installing again later will usually
+/// succeed.
+#[allow(dead_code)]
+pub fn install(
+    module: &Module,
+    name: &'static str,
+    address: usize,
+    callback: impl Fn(&mut Context) + Send + Sync + 'static,
+) -> Result<(), MidHookError> {
+    // Whole instructions covering the jump
+    let mut len = 0;
+    while len < JMP_LEN {
+        len += x86::decode_at(module, address + len)
+            .map_err(MidHookError::Decode)?
+            .len;
+    }
+    let displaced = module
+        .read(address, len)
+        .ok_or(MidHookError::Decode(DecodeError::Truncated(address)))?;
+
+    let callback: Box<Callback> = Box::new(Box::new(callback));
+    let callback_ptr = &*callback as *const Callback as usize;
+
+    // The stub's size depends on how much the displaced code grows when relocated
+    let size = stub(address, callback_ptr, displaced, address)?.len();
+    let stub_address = cave::alloc_near(module, size).map_err(MidHookError::Cave)?;
+    let code = stub(stub_address, callback_ptr, displaced, address)?;
+    memory::write_bytes(stub_address, &code).map_err(MidHookError::Patch)?;
+
+    // Jump to the stub, padding the rest of the displaced instructions with nops
+    let mut jump = vec![0x90; len];
+    jump[0] = 0xE9;
+    let rel = memory::rel32(address + JMP_LEN, stub_address).map_err(MidHookError::Patch)?;
+    jump[1..JMP_LEN].copy_from_slice(&rel.to_le_bytes());
+
+    let write = PatchWrite::exact(address, displaced, &jump);
+    memory::apply(Patch::new(name, vec![write]).with_threads(Threads::Suspended))
+        .map_err(MidHookError::Patch)?;
+
+    CALLBACKS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .push(callback);
+
+    #[cfg(debug_assertions)]
+    println!(
+        "patches: Mid-hook {} at 0x{:08X} -> stub 0x{:08X}",
+        name, address, stub_address
+    );
+
+    Ok(())
+}
+
+/// Drop every mid-hook closure
+///
+/// Only safe once the jumps to their stubs have been reverted.
+pub fn release() {
+    CALLBACKS.lock().unwrap_or_else(|e| e.into_inner()).clear();
+}
+
+/// Assemble the stub for a mid-hook at `site`, to be placed at `at`
+fn stub(
+    at: usize,
+    callback: usize,
+    displaced: &[u8],
+    site: usize,
+) -> Result<Vec<u8>, MidHookError> {
+    let mut code = vec![
+        0x9C, // pushfd
+        0x60, // pushad
+        0x83, 0x44, 0x24, 0x0C, 0x04, // add dword [esp+0Ch], 4
+        0xFC, // cld
+        0x54, // push esp
+        0x68, // push callback
+    ];
+    code.extend_from_slice(&(callback as u32).to_le_bytes());
+
+    code.push(0xE8); // call dispatch
+    let rel = memory::rel32(at + code.len() + 4, dispatch as Dispatch as usize)
+        .map_err(MidHookError::Patch)?;
+    code.extend_from_slice(&rel.to_le_bytes());
+
+    code.extend_from_slice(&[
+        0x83, 0xC4, 0x08, // add esp, 8
+        0x61, // popad
+        0x9D, // popfd
+    ]);
+
+    let relocated =
+        x86::relocate(displaced, site, at + code.len()).map_err(MidHookError::Decode)?;
+    code.extend_from_slice(&relocated);
+
+    code.push(0xE9); // jmp back
+    let rel =
+        memory::rel32(at + code.len() + 4, site + displaced.len()).map_err(MidHookError::Patch)?;
+    code.extend_from_slice(&rel.to_le_bytes());
+
+    Ok(code)
+}
+
+/// Called by every stub with its closure and the saved registers
+extern "C" fn dispatch(callback: *const Callback, context: *mut Context) {
+    unsafe { (*callback)(&mut *context) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stub_layout() {
+        // jz +5; mov eax, [esp+4]
+        let displaced = [0x74, 0x05, 0x8B, 0x44, 0x24, 0x04];
+        // Near dispatch, so a 64-bit test build can still reach it with rel32
+        let base = dispatch as Dispatch as usize & !0xFFFF;
+        let (site, at) = (base + 0x1000, base + 0x5000);
+        let code = stub(at, 0xAABBCCDD, &displaced, site).unwrap();
+
+        // Registers saved and the closure called with them
+        assert_eq!(
+            code[..14],
+            [
+                0x9C, 0x60, 0x83, 0x44, 0x24, 0x0C, 0x04, 0xFC, 0x54, 0x68, 0xDD, 0xCC, 0xBB, 0xAA
+            ]
+        );
+        assert_eq!(code[14], 0xE8);
+        let rel = i32::from_le_bytes(code[15..19].try_into().unwrap());
+        assert_eq!(
+            (at + 19).wrapping_add_signed(rel as isize),
+            dispatch as Dispatch as usize
+        );
+        assert_eq!(code[19..24], [0x83, 0xC4, 0x08, 0x61, 0x9D]);
+
+        // The short jz grows into a jz rel32 that still reaches site + 7
+        assert_eq!(code[24..26], [0x0F, 0x84]);
+        let rel = i32::from_le_bytes(code[26..30].try_into().unwrap());
+        assert_eq!((at + 30).wrapping_add_signed(rel as isize), site + 7);
+        assert_eq!(code[30..34], [0x8B, 0x44, 0x24, 0x04]);
+
+        // And back to the instruction after the displaced ones
+        assert_eq!(code[34], 0xE9);
+        let rel = i32::from_le_bytes(code[35..39].try_into().unwrap());
+        assert_eq!((at + 39).wrapping_add_signed(rel as isize), site + 6);
+        assert_eq!(code.len(), 39);
+    }
+
+    #[test]
+    fn context_matches_pushad() {
+        // pushad pushes 8 dwords below the pushfd flags
+        assert_eq!(std::mem::size_of::<Context>(), 9 * 4);
+        assert_eq!(std::mem::offset_of!(Context, esp), 3 * 4);
+        assert_eq!(std::mem::offset_of!(Context, eflags), 8 * 4);
+    }
+}
@@ -5,114 +5,429 @@
 //! we can safely patch Dunia.dll from here.
 //!
 //! All signature scans are performed upfront before any patches are applied,
-//! ensuring that patches don't corrupt signatures we haven't scanned yet.
-
+//! ensuring that patches don't corrupt signatures we haven't scanned yet. The
+//! build of Dunia.dll is identified and logged first; builds with verified
+//! addresses would skip most of the scanning, but none has been verified yet.
+
+mod asm;
+mod builds;
+mod cache;
+mod cave;
+mod config;
+#[cfg(test)]
+mod fixture;
 mod hooks;
+mod iat;
 mod memory;
+mod midhook;
+pub mod offline;
+mod operand;
+pub mod pattern;
+mod pe;
+mod rtti;
+mod siggen;
 mod sigscan;
-
-use memory::write_bytes;
-use sigscan::{Pattern, scan_module};
+mod vtable;
+mod x86;
+mod xref;
+
+use builds::{BuildId, KnownBuild};
+use cache::ScanCache;
+use memory::{Patch, Threads};
+use pattern::Pattern;
+use pe::Module;
+use sigscan::{Match, ScanError, Signature};
+use windows::Win32::Foundation::HMODULE;
 use windows::Win32::System::LibraryLoader::GetModuleHandleA;
 use windows::core::PCSTR;
+use x86::DecodeError;
 
 /// Signature definitions
+///
+/// Bytes a patch overwrites are matched as either the original or the patched
+/// byte (`{75,EB}`), so signatures still resolve once the patch (ours or another
+/// tool's) is applied. Whether a site holds the original bytes is checked
+/// separately, see [`verify`].
 mod signatures {
+    use super::Signature;
+
     // Jackal Tapes: cmp byte ptr [esi+74h], 0 | jnz short | cmp ecx, edx | jnz
-    pub const JACKAL_TAPES: &str = "80 7E 74 00 75 ?? 3B CA 75";
+    // Captures the rel8 displacement of the first jnz
+    pub const JACKAL_TAPES: Signature =
+        Signature::code("Jackal Tapes", "80 7E 74 00 75 [jmp:??] 3B CA 75");
 
     // DevMode: cmp byte ptr [ecx+offset], 0 | mov edx, [esp+arg] | jnz
-    pub const DEVMODE: &str = "80 79 ?? 00 8B 54 24 ?? 75";
+    // Patch point is the jnz opcode
+    pub const DEVMODE: Signature = Signature::code("DevMode", "80 79 ?? 00 8B 54 24 ?? | {75,EB}");
 
     // Predecessor Tapes: mov ecx, [ecx+0Ch] | test ecx, ecx | jz
     // Function checks online service pointer, patch makes it always skip the null check
-    pub const PREDECESSOR_TAPES: &str = "8B 49 0C 85 C9 74 ?? 8B 44 24";
+    // Patch point is the jz opcode
+    pub const PREDECESSOR_TAPES: Signature =
+        Signature::code("Predecessor Tapes", "8B 49 0C 85 C9 | {74,EB} ?? 8B 44 24");
 
     // Machetes: sub esp, ?? | push ebx | lea eax, [esp+??] | push eax | push
-    // This is the prologue of IsMachetesUnlocked function
-    pub const MACHETES: &str = "83 EC ?? 53 8D 44 24 ?? 50 68";
+    // This is the prologue of IsMachetesUnlocked function. The patch point is the
+    // "mov al, bl" (8A C3) that produces the return value, 0x69 bytes in
+    // (or "mov al, 1", B0 01, once patched).
+    pub const MACHETES: Signature = Signature::code(
+        "Machetes",
+        "83 EC ?? 53 8D 44 24 ?? 50 68 ??{95} | {8A,B0} {C3,01}",
+    );
 
-    // No Blinking Items: String literals to corrupt
-    pub const MESH_HIGHLIGHT: &str = "4D 65 73 68 5F 48 69 67 68 6C 69 67 68 74"; // "Mesh_Highlight"
-    pub const ARCH_BLINK: &str = "61 72 63 68 42 6C 69 6E 6B"; // "archBlink"
-    pub const SAVE_DISK: &str = "67 61 64 67 65 74 73 2E 4F 62 6A 65 63 74 69 76 65 49 63 6F 6E 73 2E 53 61 76 65 44 69 73 6B"; // "gadgets.ObjectiveIcons.SaveDisk"
+    // No Blinking Items: String literals to corrupt, patch point is the character to replace
+    // (the original character or '.')
+    pub const MESH_HIGHLIGHT: Signature = Signature::data(
+        "Mesh_Highlight",
+        "4D 65 73 68 | {5F,2E} 48 69 67 68 6C 69 67 68 74", // "Mesh_Highlight", '_'
+    );
+    pub const ARCH_BLINK: Signature = Signature::data(
+        "archBlink",
+        "61 72 63 68 42 6C 69 6E | {6B,2E}", // "archBlink", 'k'
+    );
+    pub const SAVE_DISK: Signature = Signature::data(
+        "SaveDisk",
+        "67 61 64 67 65 74 73 2E 4F 62 6A 65 63 74 69 76 65 49 63 6F 6E 73 2E 53 61 76 65 44 69 73 | {6B,2E}", // "gadgets.ObjectiveIcons.SaveDisk", 'k'
+    );
 }
 
 /// Cached addresses from signature scans (found before patching)
 #[allow(dead_code)]
 struct PatchAddresses {
-    jackal_tapes: Option<usize>,
-    devmode: Option<usize>,
-    predecessor_tapes: Option<usize>,
-    machetes: Option<usize>,
-    mesh_highlight: Option<usize>,
-    arch_blink: Option<usize>,
-    save_disk: Option<usize>,
+    /// The build scanned, if it is a known build
+    build: Option<&'static KnownBuild>,
+    jackal_tapes: Result<Match, ScanError>,
+    devmode: Result<Match, ScanError>,
+    predecessor_tapes: Result<Match, ScanError>,
+    machetes: Result<Match, ScanError>,
+    mesh_highlight: Result<Match, ScanError>,
+    arch_blink: Result<Match, ScanError>,
+    save_disk: Result<Match, ScanError>,
 }
 
 impl PatchAddresses {
     /// Scan for all signatures upfront, before any patches are applied
-    fn scan(base: usize) -> Self {
+    ///
+    /// `hooks` are resolved in the same batch, and their results returned in
+    /// order. Addresses supplied by `known` (a build's table or the scan cache)
+    /// are used when they still match their signature; everything else is
+    /// scanned for.
+    fn scan(
+        module: &Module,
+        build: Option<&'static KnownBuild>,
+        known: impl Fn(&Signature) -> Option<usize>,
+        hooks: &[&'static Signature],
+    ) -> (Self, Vec<Result<Match, ScanError>>) {
         #[cfg(debug_assertions)]
         println!("patches: Scanning for all signatures...");
 
-        let jackal_tapes =
-            Pattern::parse(signatures::JACKAL_TAPES).and_then(|p| scan_module(base, &p));
-        let devmode = Pattern::parse(signatures::DEVMODE).and_then(|p| scan_module(base, &p));
-        let predecessor_tapes =
-            Pattern::parse(signatures::PREDECESSOR_TAPES).and_then(|p| scan_module(base, &p));
-        let machetes = Pattern::parse(signatures::MACHETES).and_then(|p| scan_module(base, &p));
-        let mesh_highlight =
-            Pattern::parse(signatures::MESH_HIGHLIGHT).and_then(|p| scan_module(base, &p));
-        let arch_blink = Pattern::parse(signatures::ARCH_BLINK).and_then(|p| scan_module(base, &p));
-        let save_disk = Pattern::parse(signatures::SAVE_DISK).and_then(|p| scan_module(base, &p));
+        #[cfg(debug_assertions)]
+        let started = std::time::Instant::now();
+
+        let patches = [
+            &signatures::JACKAL_TAPES,
+            &signatures::DEVMODE,
+            &signatures::PREDECESSOR_TAPES,
+            &signatures::MACHETES,
+            &signatures::MESH_HIGHLIGHT,
+            &signatures::ARCH_BLINK,
+            &signatures::SAVE_DISK,
+        ];
+        let mut results =
+            sigscan::resolve_all_known(module, &[&patches[..], hooks].concat(), known).into_iter();
+        let mut next = || results.next().unwrap_or(Err(ScanError::NotFound));
+
+        #[cfg(debug_assertions)]
+        println!(
+            "patches: Scan finished in {:.2} ms",
+            started.elapsed().as_secs_f64() * 1000.0
+        );
+
+        let addrs = Self {
+            build,
+            jackal_tapes: next(),
+            devmode: next(),
+            predecessor_tapes: next(),
+            machetes: next(),
+            mesh_highlight: next(),
+            arch_blink: next(),
+            save_disk: next(),
+        };
+        let hook_addrs: Vec<_> = hooks.iter().map(|_| next()).collect();
 
         #[cfg(debug_assertions)]
+        for (signature, result) in addrs
+            .entries()
+            .into_iter()
+            .chain(hooks.iter().copied().zip(&hook_addrs))
         {
-            println!(
-                "patches:   Jackal Tapes:      {:?}",
-                jackal_tapes.map(|a| format!("0x{:08X}", a))
-            );
-            println!(
-                "patches:   DevMode:           {:?}",
-                devmode.map(|a| format!("0x{:08X}", a))
-            );
-            println!(
-                "patches:   Predecessor Tapes: {:?}",
-                predecessor_tapes.map(|a| format!("0x{:08X}", a))
-            );
-            println!(
-                "patches:   Machetes:          {:?}",
-                machetes.map(|a| format!("0x{:08X}", a))
-            );
-            println!(
-                "patches:   Mesh_Highlight:    {:?}",
-                mesh_highlight.map(|a| format!("0x{:08X}", a))
-            );
-            println!(
-                "patches:   archBlink:         {:?}",
-                arch_blink.map(|a| format!("0x{:08X}", a))
-            );
-            println!(
-                "patches:   SaveDisk:          {:?}",
-                save_disk.map(|a| format!("0x{:08X}", a))
-            );
+            log_resolved(signature, result);
         }
 
+        (addrs, hook_addrs)
+    }
+
+    /// Every signature paired with its scan result, in declaration order
+    fn entries(&self) -> [(&'static Signature, &Result<Match, ScanError>); 7] {
+        [
+            (&signatures::JACKAL_TAPES, &self.jackal_tapes),
+            (&signatures::DEVMODE, &self.devmode),
+            (&signatures::PREDECESSOR_TAPES, &self.predecessor_tapes),
+            (&signatures::MACHETES, &self.machetes),
+            (&signatures::MESH_HIGHLIGHT, &self.mesh_highlight),
+            (&signatures::ARCH_BLINK, &self.arch_blink),
+            (&signatures::SAVE_DISK, &self.save_disk),
+        ]
+    }
+}
+
+/// Print the outcome of resolving a signature
+#[cfg(debug_assertions)]
+fn log_resolved(signature: &Signature, result: &Result<Match, ScanError>) {
+    let name = format!("{}:", signature.name);
+    match result {
+        Ok(m) => println!("patches:   {:<18} 0x{:08X}", name, m.address),
+        Err(e) => println!("patches:   {:<18} {}", name, e),
+    }
+}
+
+/// A single write made by a patch
+pub struct PatchWrite {
+    pub address: usize,
+    /// What must be at `address` before writing
+    pub expected: Pattern,
+    pub bytes: Vec<u8>,
+    /// A known instruction boundary at or before `address`, for writes to code
+    pub decoded_from: Option<usize>,
+}
+
+impl PatchWrite {
+    /// Replace the bytes matching `expected` (pattern syntax) at `address` with `bytes`
+    fn new(address: usize, expected: &str, bytes: &[u8]) -> Result<Self, ScanError> {
+        Ok(Self {
+            address,
+            expected: Pattern::parse(expected).map_err(ScanError::InvalidPattern)?,
+            bytes: bytes.to_vec(),
+            decoded_from: None,
+        })
+    }
+
+    /// Replace exactly `expected` at `address` with `bytes`
+    ///
+    /// For writes whose original bytes were read at runtime rather than matched
+    /// by a signature, such as a pointer slot being redirected.
+    fn exact(address: usize, expected: &[u8], bytes: &[u8]) -> Self {
         Self {
-            jackal_tapes,
-            devmode,
-            predecessor_tapes,
-            machetes,
-            mesh_highlight,
-            arch_blink,
-            save_disk,
+            address,
+            expected: Pattern::exact(expected),
+            bytes: bytes.to_vec(),
+            decoded_from: None,
+        }
+    }
+
+    /// Check the write against the instructions at its site, decoding from `start`
+    ///
+    /// The write must begin on an instruction boundary, and the patched code
+    /// must end on the same boundary as the original instructions it overwrites,
+    /// so no instruction is left split.
+    fn decoded_from(mut self, start: usize) -> Self {
+        self.decoded_from = Some(start);
+        self
+    }
+}
+
+/// Why the bytes at a patch site are not the ones a patch expects
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SiteMismatch {
+    /// Every site already holds the patched bytes
+    AlreadyApplied,
+    /// The build is known, so something else (another mod) changed these bytes
+    ForeignModification { address: usize, found: Vec<u8> },
+    /// The build is not known, so the code may simply be different
+    UnexpectedBuild { address: usize, found: Vec<u8> },
+    /// The write at this address would split an instruction
+    SplitInstruction(usize),
+    /// The code around a write could not be decoded
+    Undecodable(DecodeError),
+    /// The write is not the same size as the bytes it is meant to replace
+    SizeMismatch {
+        address: usize,
+        expected: usize,
+        written: usize,
+    },
+}
+
+impl std::fmt::Display for SiteMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SiteMismatch::AlreadyApplied => write!(f, "already applied"),
+            SiteMismatch::ForeignModification { address, found } => write!(
+                f,
+                "foreign modification at 0x{:08X} (found {})",
+                address,
+                pattern::hex(found)
+            ),
+            SiteMismatch::UnexpectedBuild { address, found } => write!(
+                f,
+                "unexpected build, found {} at 0x{:08X}",
+                pattern::hex(found),
+                address
+            ),
+            SiteMismatch::SplitInstruction(address) => {
+                write!(f, "write at 0x{:08X} splits an instruction", address)
+            }
+            SiteMismatch::Undecodable(e) => write!(f, "{}", e),
+            SiteMismatch::SizeMismatch {
+                address,
+                expected,
+                written,
+            } => write!(
+                f,
+                "write at 0x{:08X} is {} bytes, the site is {}",
+                address, written, expected
+            ),
         }
     }
 }
 
+/// Check that every site of a patch holds the bytes the patch expects
+///
+/// `known_build` decides how a mismatch is reported: on a known build the
+/// original bytes are certain, so a difference means someone else patched them.
+fn verify(module: &Module, writes: &[PatchWrite], known_build: bool) -> Result<(), SiteMismatch> {
+    if let Some(write) = writes.iter().find(|w| w.expected.len() != w.bytes.len()) {
+        return Err(SiteMismatch::SizeMismatch {
+            address: write.address,
+            expected: write.expected.len(),
+            written: write.bytes.len(),
+        });
+    }
+
+    let current: Vec<&[u8]> = writes
+        .iter()
+        .map(|write| module.read(write.address, write.bytes.len()).unwrap_or(&[]))
+        .collect();
+
+    if writes
+        .iter()
+        .zip(&current)
+        .all(|(write, found)| write.bytes == *found)
+    {
+        return Err(SiteMismatch::AlreadyApplied);
+    }
+
+    for (write, found) in writes.iter().zip(current) {
+        if found.len() == write.bytes.len() && write.expected.matches(found) {
+            continue;
+        }
+
+        let (address, found) = (write.address, found.to_vec());
+        return Err(if known_build {
+            SiteMismatch::ForeignModification { address, found }
+        } else {
+            SiteMismatch::UnexpectedBuild { address, found }
+        });
+    }
+
+    for write in writes {
+        if let Some(start) = write.decoded_from {
+            verify_instructions(module, write, start)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Check that `write` starts on an instruction boundary and leaves whole instructions
+fn verify_instructions(
+    module: &Module,
+    write: &PatchWrite,
+    start: usize,
+) -> Result<(), SiteMismatch> {
+    let split = SiteMismatch::SplitInstruction(write.address);
+    let decode = |address| x86::decode_at(module, address).map_err(SiteMismatch::Undecodable);
+
+    let mut next = start;
+    while next < write.address {
+        next = decode(next)?.end();
+    }
+    if next != write.address {
+        return Err(split);
+    }
+
+    // The original instructions covering the write
+    let end = write.address + write.bytes.len();
+    while next < end {
+        next = decode(next)?.end();
+    }
+
+    // The patched code must decode to instructions ending at the same place
+    let mut patched = module
+        .read(write.address, next - write.address)
+        .ok_or(split.clone())?
+        .to_vec();
+    patched[..write.bytes.len()].copy_from_slice(&write.bytes);
+    match x86::span(&patched, write.address, write.bytes.len()) {
+        Ok(len) if len == patched.len() => Ok(()),
+        _ => Err(split),
+    }
+}
+
+/// A patch to Dunia.dll
+///
+/// Patches only describe their writes, so the same definition can be applied
+/// to the running game or to a copy of Dunia.dll on disk (see [`offline`]).
+struct PatchDef {
+    name: &'static str,
+    /// Whether the patch is applied when the config does not say
+    enabled: bool,
+    writes: fn(&PatchAddresses) -> Result<Vec<PatchWrite>, ScanError>,
+}
+
+impl PatchDef {
+    /// Whether the patch is turned on, by the config or else by default
+    fn is_enabled(&self) -> bool {
+        config::get(&self.config_key()).unwrap_or(self.enabled)
+    }
+
+    fn config_key(&self) -> String {
+        format!("patch.{}", self.name)
+    }
+}
+
+/// All patches, in the order they are applied
+const PATCHES: &[PatchDef] = &[
+    PatchDef {
+        name: "Jackal Tapes",
+        enabled: true,
+        writes: jackal_tapes_fix,
+    },
+    PatchDef {
+        name: "No Blinking Items",
+        enabled: false,
+        writes: no_blinking_items,
+    },
+    PatchDef {
+        name: "DevMode",
+        enabled: true,
+        writes: devmode_unlock,
+    },
+    PatchDef {
+        name: "Predecessor Tapes",
+        enabled: true,
+        writes: predecessor_tapes_unlock,
+    },
+    PatchDef {
+        name: "Machetes",
+        enabled: true,
+        writes: machetes_unlock,
+    },
+];
+
 /// Apply all enabled patches to Dunia.dll
-pub fn apply_patches() {
+///
+/// `dll` is systemdetection.dll itself; the scan cache is kept next to it.
+pub fn apply_patches(dll: HMODULE) {
     // Get Dunia.dll base address
     let dunia_base =
         unsafe { GetModuleHandleA(PCSTR::from_raw(c"Dunia.dll".as_ptr() as *const u8)) };
@@ -134,22 +449,160 @@ pub fn apply_patches() {
     #[cfg(debug_assertions)]
     println!("patches: Dunia.dll base = 0x{:08X}", base);
 
+    let module = match unsafe { Module::from_base(base) } {
+        Ok(module) => module,
+        Err(_e) => {
+            #[cfg(debug_assertions)]
+            println!(
+                "patches: Failed to parse Dunia.dll headers ({}), skipping patches",
+                _e
+            );
+            return;
+        }
+    };
+
+    // Identify the build while the code is still unmodified
+    let build_id = builds::BuildId::of(&module);
+    let build = builds::identify(&build_id);
+
+    #[cfg(debug_assertions)]
+    match build {
+        Some(build) => println!("patches: Dunia.dll build: {}", build.name),
+        None => println!("patches: Unknown Dunia.dll build ({})", build_id),
+    }
+
+    if let Some(path) = cache::beside(dll, config::FILE_NAME) {
+        config::load(&path);
+    }
+
+    let cache_path = ScanCache::path(dll);
+    let cache = cache_path
+        .as_deref()
+        .and_then(|path| ScanCache::load(path, &build_id));
+
+    #[cfg(debug_assertions)]
+    if cache.is_some() {
+        println!("patches: Using cached signature addresses");
+    }
+
+    // Verified addresses come from the build's table first, then the scan cache
+    let known = |signature: &Signature| {
+        build
+            .and_then(|build| build.address(&module, signature.name))
+            .or_else(|| cache.as_ref()?.address(&module, signature.name))
+    };
+
     // IMPORTANT: Scan for ALL signatures BEFORE applying any patches
     // This prevents patches from corrupting signatures we haven't found yet
-    let addrs = PatchAddresses::scan(base);
-    let hook_addrs = hooks::HookAddresses::scan(base);
+    let hook_signatures = hooks::signatures();
+    let (addrs, hook_addrs) = PatchAddresses::scan(&module, build, known, &hook_signatures);
+
+    if let Some(path) = &cache_path {
+        let resolved = addrs
+            .entries()
+            .into_iter()
+            .chain(hook_signatures.iter().copied().zip(&hook_addrs));
+        update_cache(path, &module, build_id, cache.as_ref(), resolved);
+    }
 
     // Now apply patches using the cached addresses
-    apply_jackal_tapes_fix(&addrs);
-    // apply_no_blinking_items(&addrs);
-    apply_devmode_unlock(&addrs);
-    apply_predecessor_tapes_unlock(&addrs);
-    apply_machetes_unlock(&addrs);
+    let known_build = build.is_some();
+    for patch in PATCHES.iter().filter(|p| p.is_enabled()) {
+        if let Err(_e) = apply_patch(&module, &addrs, known_build, patch) {
+            #[cfg(debug_assertions)]
+            match _e {
+                ApplyError::Patch(e) => println!("patches: {} failed: {}", patch.name, e),
+                e => println!("patches: {} skipped: {}", patch.name, e),
+            }
+        }
+    }
 
     // Install function hooks (for FOV slider, etc.)
-    if let Err(_e) = hooks::install_hooks(&hook_addrs) {
-        #[cfg(debug_assertions)]
-        println!("patches: Failed to install hooks: {:?}", _e);
+    hooks::install_hooks(&hook_addrs);
+}
+
+/// Why a patch could not be applied
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ApplyError {
+    /// A signature the patch needs did not resolve
+    Scan(ScanError),
+    /// The bytes at a site are not the ones the patch expects
+    Site(SiteMismatch),
+    /// The bytes could not be written
+    Patch(memory::PatchError),
+}
+
+impl std::fmt::Display for ApplyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApplyError::Scan(e) => write!(f, "{}", e),
+            ApplyError::Site(e) => write!(f, "{}", e),
+            ApplyError::Patch(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Verify and apply one patch to the running game
+fn apply_patch(
+    module: &Module,
+    addrs: &PatchAddresses,
+    known_build: bool,
+    patch: &PatchDef,
+) -> Result<(), ApplyError> {
+    let writes = (patch.writes)(addrs).map_err(ApplyError::Scan)?;
+    verify(module, &writes, known_build).map_err(ApplyError::Site)?;
+
+    #[cfg(debug_assertions)]
+    println!("patches: Applying {}", patch.name);
+
+    // Game threads may already be running, so never let them see half a patch
+    memory::apply(Patch::new(patch.name, writes).with_threads(Threads::Suspended))
+        .map_err(ApplyError::Patch)
+}
+
+/// Remove every hook and revert every applied patch, restoring Dunia.dll's original code
+///
+/// Code caves, mid-hook closures and shadow vtables are only released if
+/// every patch was reverted, since a patch left in place may still use them.
+pub fn revert_patches() {
+    hooks::remove_hooks();
+
+    if memory::revert_all() {
+        midhook::release();
+        vtable::release();
+        cave::release();
+    }
+}
+
+/// Save the addresses of the patch and hook signatures if they differ from the loaded cache
+fn update_cache<'a>(
+    path: &std::path::Path,
+    module: &Module,
+    build: BuildId,
+    loaded: Option<&ScanCache>,
+    resolved: impl Iterator<Item = (&'static Signature, &'a Result<Match, ScanError>)>,
+) {
+    let addresses = resolved
+        .filter_map(|(signature, result)| {
+            let rva = result.as_ref().ok()?.address - module.base;
+            Some((signature.name.to_string(), rva as u32))
+        })
+        .collect();
+    let cache = ScanCache { build, addresses };
+
+    if loaded == Some(&cache) {
+        return;
+    }
+
+    match cache.save(path) {
+        Ok(()) => {
+            #[cfg(debug_assertions)]
+            println!("patches: Saved scan cache to {}", path.display());
+        }
+        Err(_e) => {
+            #[cfg(debug_assertions)]
+            println!("patches: Failed to save scan cache: {}", _e);
+        }
     }
 }
 
@@ -157,117 +610,170 @@ pub fn apply_patches() {
 ///
 /// The bug: In the Southern map, some Jackal tape pickups play incorrect recordings.
 /// This is caused by an incorrect jump offset in the tape lookup logic.
-fn apply_jackal_tapes_fix(addrs: &PatchAddresses) {
-    let Some(addr) = addrs.jackal_tapes else {
-        #[cfg(debug_assertions)]
-        println!("patches: Jackal Tapes signature not found, skipping");
-        return;
+///
+/// The original offset differs between builds, and the patched offset matches
+/// the signature just as well. Known builds record the original, so a site that
+/// already holds the patched offset is reported as applied; on other builds the
+/// offset found is taken to be the original.
+fn jackal_tapes_fix(addrs: &PatchAddresses) -> Result<Vec<PatchWrite>, ScanError> {
+    let m = addrs.jackal_tapes.as_ref().map_err(|e| *e)?;
+    let jmp = m.capture("jmp").ok_or(ScanError::NotFound)?;
+    let original = addrs
+        .build
+        .and_then(|build| build.original(signatures::JACKAL_TAPES.name))
+        .unwrap_or(&jmp.bytes);
+    let &[displacement] = original else {
+        return Err(ScanError::BadOriginal);
     };
 
-    // The jump offset byte is at offset 5 in the pattern (after "75")
-    let patch_addr = addr + 5;
-
-    #[cfg(debug_assertions)]
-    println!("patches: Applying Jackal Tapes fix at 0x{:08X}", patch_addr);
-
     // Change jump offset (add 0x10 to fix tape index calculation)
-    let current = unsafe { *(patch_addr as *const u8) };
-    write_bytes(patch_addr, &[current.wrapping_add(0x10)]);
+    Ok(vec![PatchWrite::exact(
+        jmp.address,
+        original,
+        &[displacement.wrapping_add(0x10)],
+    )])
 }
 
 /// Visual: No Blinking Items - Remove highlight blinking on interactables
 ///
 /// Patches string literals to break the shader lookup, disabling the blinking effect.
-#[allow(dead_code)]
-fn apply_no_blinking_items(addrs: &PatchAddresses) {
-    // Patch "Mesh_Highlight" - change '_' to '.'
-    if let Some(addr) = addrs.mesh_highlight {
-        #[cfg(debug_assertions)]
-        println!("patches: Patching Mesh_Highlight at 0x{:08X}", addr);
-        write_bytes(addr + 4, &[0x2E]); // offset 4 = '_'
-    }
-
-    // Patch "archBlink" - change 'k' to '.'
-    if let Some(addr) = addrs.arch_blink {
-        #[cfg(debug_assertions)]
-        println!("patches: Patching archBlink at 0x{:08X}", addr);
-        write_bytes(addr + 8, &[0x2E]); // offset 8 = 'k'
-    }
-
-    // Patch "gadgets.ObjectiveIcons.SaveDisk" - change 'k' to '.'
-    if let Some(addr) = addrs.save_disk {
-        #[cfg(debug_assertions)]
-        println!("patches: Patching SaveDisk at 0x{:08X}", addr);
-        write_bytes(addr + 30, &[0x2E]); // offset 30 = 'k'
-    }
+fn no_blinking_items(addrs: &PatchAddresses) -> Result<Vec<PatchWrite>, ScanError> {
+    // "Mesh_Highlight" - change '_' to '.'
+    // "archBlink" - change 'k' to '.'
+    // "gadgets.ObjectiveIcons.SaveDisk" - change 'k' to '.'
+    [
+        (&addrs.mesh_highlight, "5F"),
+        (&addrs.arch_blink, "6B"),
+        (&addrs.save_disk, "6B"),
+    ]
+    .into_iter()
+    .map(|(m, expected)| {
+        let m = m.as_ref().map_err(|e| *e)?;
+        PatchWrite::new(m.patch_point, expected, &[0x2E])
+    })
+    .collect()
 }
 
 /// Fix: DevMode Unlock - Enable developer console commands
 ///
 /// Patches CConsoleService_IsCommandVisible to always skip the devmode check,
 /// making all "ConsoleDeveloperOnly" commands visible and usable.
-fn apply_devmode_unlock(addrs: &PatchAddresses) {
-    let Some(addr) = addrs.devmode else {
-        #[cfg(debug_assertions)]
-        println!("patches: DevMode signature not found, skipping");
-        return;
-    };
-
-    // The jnz opcode is at offset 8 in the pattern
-    let jnz_addr = addr + 8;
-
-    #[cfg(debug_assertions)]
-    println!("patches: Applying DevMode unlock at 0x{:08X}", jnz_addr);
+fn devmode_unlock(addrs: &PatchAddresses) -> Result<Vec<PatchWrite>, ScanError> {
+    let m = addrs.devmode.as_ref().map_err(|e| *e)?;
 
     // Change jnz (0x75) to jmp (0xEB) - always skip the devmode check
-    write_bytes(jnz_addr, &[0xEB]);
+    Ok(vec![
+        PatchWrite::new(m.patch_point, "75", &[0xEB])?.decoded_from(m.address),
+    ])
 }
 
 /// Unlock: Predecessor Tapes - Unlock 7 bonus missions
 ///
 /// The predecessor tapes were originally tied to an online Ubisoft account.
 /// This patches IsPredecessorTapesUnlocked to always return true.
-fn apply_predecessor_tapes_unlock(addrs: &PatchAddresses) {
-    let Some(addr) = addrs.predecessor_tapes else {
-        #[cfg(debug_assertions)]
-        println!("patches: Predecessor Tapes signature not found, skipping");
-        return;
-    };
-
-    // Pattern: 8B 49 0C 85 C9 74 ?? 8B 44 24
-    // The jz opcode is at offset 5, jump offset at offset 6
-    // We change "74 ??" (jz) to "EB 0E" (jmp +14) to skip the null check
-    let jz_addr = addr + 5;
-
-    #[cfg(debug_assertions)]
-    println!(
-        "patches: Applying Predecessor Tapes unlock at 0x{:08X}",
-        jz_addr
-    );
-
-    // Change jz (0x74) to jmp (0xEB), and set offset to 0x0E
-    write_bytes(jz_addr, &[0xEB, 0x0E]);
+fn predecessor_tapes_unlock(addrs: &PatchAddresses) -> Result<Vec<PatchWrite>, ScanError> {
+    let m = addrs.predecessor_tapes.as_ref().map_err(|e| *e)?;
+
+    // Change the jz to an unconditional jump over the null check
+    let jump = const { asm::assemble("jmp short +0x0E") };
+    Ok(vec![
+        PatchWrite::new(m.patch_point, "74 ??", jump.bytes())?.decoded_from(m.address),
+    ])
 }
 
 /// Unlock: Machetes - Unlock 2 bonus machete skins
 ///
 /// The bonus machetes were originally unlocked via a registry key.
 /// This patches IsMachetesUnlocked to always return true.
-fn apply_machetes_unlock(addrs: &PatchAddresses) {
-    let Some(addr) = addrs.machetes else {
-        #[cfg(debug_assertions)]
-        println!("patches: Machetes signature not found, skipping");
-        return;
+fn machetes_unlock(addrs: &PatchAddresses) -> Result<Vec<PatchWrite>, ScanError> {
+    let m = addrs.machetes.as_ref().map_err(|e| *e)?;
+
+    // Change "mov al, bl" to "mov al, 1" to always return true
+    let original = const { asm::assemble("mov al, bl") };
+    let patched = const { asm::assemble("mov al, 1") };
+    Ok(vec![
+        PatchWrite::exact(m.patch_point, original.bytes(), patched.bytes()).decoded_from(m.address),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::patches::fixture::{self, CODE, IMAGE_BASE};
+
+    /// A build whose Jackal Tapes jump originally skips 0x20 bytes
+    static BUILD: KnownBuild = KnownBuild {
+        name: "Test",
+        id: BuildId {
+            timestamp: 0,
+            checksum: 0,
+            code_hash: 0,
+        },
+        addresses: &[],
+        originals: &[("Jackal Tapes", &[0x20])],
     };
 
-    // Signature is at function prologue (sub esp | push ebx | lea eax | push eax | push)
-    // Offset from function start to "mov al, bl" (8A C3) is 0x69 (105 bytes)
-    // We change "mov al, bl" to "mov al, 1" (B0 01) to always return true
-    let patch_addr = addr + 0x69;
+    /// The Jackal Tapes site with `displacement` in its jnz
+    fn jackal(displacement: u8) -> Module {
+        let mut code = vec![0xCC; 0x40];
+        code[0x10..0x19].copy_from_slice(&[
+            0x80,
+            0x7E,
+            0x74,
+            0x00,
+            0x75,
+            displacement,
+            0x3B,
+            0xCA,
+            0x75,
+        ]);
+        fixture::module(&[(".text", 0x1000, code, CODE)], &[])
+    }
 
-    #[cfg(debug_assertions)]
-    println!("patches: Applying Machetes unlock at 0x{:08X}", patch_addr);
+    fn jackal_writes(
+        module: &Module,
+        build: Option<&'static KnownBuild>,
+    ) -> Result<Vec<PatchWrite>, ScanError> {
+        let (addrs, _) = PatchAddresses::scan(module, build, |_| None, &[]);
+        jackal_tapes_fix(&addrs)
+    }
+
+    #[test]
+    fn jackal_tapes_adds_to_the_original_displacement() {
+        let module = jackal(0x20);
+        let writes = jackal_writes(&module, Some(&BUILD)).unwrap();
+
+        assert_eq!(writes[0].address, IMAGE_BASE + 0x1015);
+        assert_eq!(writes[0].bytes, [0x30]);
+        assert_eq!(verify(&module, &writes, true), Ok(()));
+    }
+
+    #[test]
+    fn jackal_tapes_detects_applied_and_foreign_fixes() {
+        let module = jackal(0x30);
+        let writes = jackal_writes(&module, Some(&BUILD)).unwrap();
+        assert_eq!(
+            verify(&module, &writes, true),
+            Err(SiteMismatch::AlreadyApplied)
+        );
+
+        let module = jackal(0x28);
+        let writes = jackal_writes(&module, Some(&BUILD)).unwrap();
+        assert_eq!(
+            verify(&module, &writes, true),
+            Err(SiteMismatch::ForeignModification {
+                address: IMAGE_BASE + 0x1015,
+                found: vec![0x28]
+            })
+        );
+    }
 
-    // Change "mov al, bl" (8A C3) to "mov al, 1" (B0 01)
-    write_bytes(patch_addr, &[0xB0, 0x01]);
+    #[test]
+    fn jackal_tapes_on_unknown_builds_adds_to_the_current_displacement() {
+        let module = jackal(0x28);
+        let writes = jackal_writes(&module, None).unwrap();
+
+        assert_eq!(writes[0].bytes, [0x38]);
+        assert_eq!(verify(&module, &writes, false), Ok(()));
+    }
 }
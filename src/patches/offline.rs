@@ -0,0 +1,196 @@
+//! Scanning and patching Dunia.dll files on disk
+//!
+//! The signatures and patch definitions used at runtime are applied to a copy
+//! of Dunia.dll loaded from a file instead of the game's memory. The file is
+//! mapped through its section table ([`Module::from_file`]), so addresses are
+//! reported as they would be at the image's preferred base, alongside their
+//! RVAs. This lets new game builds and signatures be checked without launching
+//! the game.
+
+use crate::patches::builds::{self, BuildId, KnownBuild};
+use crate::patches::pe::{Module, PeError};
+use crate::patches::siggen::{self, Generated, SigGenError};
+use crate::patches::sigscan::{ScanError, Signature};
+use crate::patches::{PATCHES, PatchAddresses, PatchWrite, SiteMismatch, hooks, verify};
+
+/// Where a signature resolved in a file, or why it did not
+pub struct SignatureReport {
+    pub name: &'static str,
+    /// Address at the preferred image base
+    pub result: Result<usize, ScanError>,
+    /// True for hook targets, false for patch signatures
+    pub hook: bool,
+    /// True if the address came from the known build's table rather than a scan
+    pub from_table: bool,
+}
+
+/// Outcome of applying one patch to a file
+pub struct PatchReport {
+    pub name: &'static str,
+    pub enabled: bool,
+    /// Addresses written, at the preferred image base
+    pub result: Result<Vec<usize>, PatchFileError>,
+}
+
+/// Reasons a patch could not be applied to a file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PatchFileError {
+    /// The patch's signatures did not resolve
+    Scan(ScanError),
+    /// The patch sites do not hold the expected bytes
+    Site(SiteMismatch),
+    /// The patch writes to an address with no raw data in the file
+    Unmapped(usize),
+}
+
+impl std::fmt::Display for PatchFileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PatchFileError::Scan(e) => write!(f, "{}", e),
+            PatchFileError::Site(e) => write!(f, "{}", e),
+            PatchFileError::Unmapped(address) => {
+                write!(f, "0x{:08X} has no raw data in the file", address)
+            }
+        }
+    }
+}
+
+/// A Dunia.dll file mapped for scanning
+pub struct DuniaFile {
+    module: Module,
+    build_id: BuildId,
+    build: Option<&'static KnownBuild>,
+}
+
+impl DuniaFile {
+    /// Parse and map the contents of a Dunia.dll file
+    pub fn parse(file: &[u8]) -> Result<Self, PeError> {
+        let module = Module::from_file(file)?;
+        let build_id = BuildId::of(&module);
+
+        Ok(Self {
+            build: builds::identify(&build_id),
+            build_id,
+            module,
+        })
+    }
+
+    /// What identifies this build of Dunia.dll
+    pub fn build_id(&self) -> BuildId {
+        self.build_id
+    }
+
+    /// Name of the build, if it is a known build
+    pub fn build_name(&self) -> Option<&'static str> {
+        self.build.map(|build| build.name)
+    }
+
+    /// Preferred image base; all reported addresses are relative to it
+    pub fn image_base(&self) -> usize {
+        self.module.base
+    }
+
+    /// Resolve every patch and hook signature
+    pub fn scan(&self) -> Vec<SignatureReport> {
+        let hook_signatures = hooks::signatures();
+        let (addrs, hook_addrs) = PatchAddresses::scan(
+            &self.module,
+            self.build,
+            |s| self.table_address(s),
+            &hook_signatures,
+        );
+
+        let patches = addrs
+            .entries()
+            .map(|(signature, result)| (signature, result, false));
+        let hooks = hook_signatures
+            .into_iter()
+            .zip(&hook_addrs)
+            .map(|(signature, result)| (signature, result, true));
+
+        patches
+            .into_iter()
+            .chain(hooks)
+            .map(|(signature, result, hook)| {
+                let result = result.as_ref().map(|m| m.address).map_err(|e| *e);
+                let table = self.table_address(signature);
+
+                SignatureReport {
+                    name: signature.name,
+                    from_table: table.is_some() && table == result.ok(),
+                    result,
+                    hook,
+                }
+            })
+            .collect()
+    }
+
+    /// The shortest signature that uniquely identifies `rva`
+    pub fn signature(&self, rva: usize) -> Result<Generated, SigGenError> {
+        siggen::generate(&self.module, self.module.base + rva)
+    }
+
+    /// Apply every enabled patch to `file`, which must be the file this was parsed from
+    ///
+    /// Disabled patches are reported but not written. A patch whose writes cannot
+    /// all be placed in the file is skipped entirely.
+    pub fn patch(&self, file: &mut [u8]) -> Vec<PatchReport> {
+        let (addrs, _) =
+            PatchAddresses::scan(&self.module, self.build, |s| self.table_address(s), &[]);
+
+        PATCHES
+            .iter()
+            .map(|patch| {
+                let result = if patch.enabled {
+                    self.apply(file, &addrs, patch.writes)
+                } else {
+                    Ok(Vec::new())
+                };
+
+                PatchReport {
+                    name: patch.name,
+                    enabled: patch.enabled,
+                    result,
+                }
+            })
+            .collect()
+    }
+
+    /// Address of `signature` from the known build's table
+    fn table_address(&self, signature: &Signature) -> Option<usize> {
+        self.build?.address(&self.module, signature.name)
+    }
+
+    fn apply(
+        &self,
+        file: &mut [u8],
+        addrs: &PatchAddresses,
+        writes: fn(&PatchAddresses) -> Result<Vec<PatchWrite>, ScanError>,
+    ) -> Result<Vec<usize>, PatchFileError> {
+        let writes = writes(addrs).map_err(PatchFileError::Scan)?;
+        verify(&self.module, &writes, self.build.is_some()).map_err(PatchFileError::Site)?;
+
+        // Map every write before touching the file so a patch is all or nothing
+        let mut placed = Vec::with_capacity(writes.len());
+        for write in &writes {
+            let offset = self
+                .file_offset(write.address, write.bytes.len(), file.len())
+                .ok_or(PatchFileError::Unmapped(write.address))?;
+            placed.push((offset, &write.bytes));
+        }
+
+        for (offset, bytes) in placed {
+            file[offset..offset + bytes.len()].copy_from_slice(bytes);
+        }
+
+        Ok(writes.iter().map(|write| write.address).collect())
+    }
+
+    /// File offset of `len` bytes at `address`, which must lie in one section's raw data
+    fn file_offset(&self, address: usize, len: usize, file_len: usize) -> Option<usize> {
+        let rva = address.checked_sub(self.module.base)?;
+        let start = self.module.headers.file_offset(rva)?;
+        let last = self.module.headers.file_offset(rva + len - 1)?;
+        (last == start + len - 1 && last < file_len).then_some(start)
+    }
+}
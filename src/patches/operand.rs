@@ -0,0 +1,306 @@
+//! Operand resolution for following code from a signature match
+//!
+//! Given the address of an instruction (typically a match's patch point or
+//! capture), these helpers decode relative branch targets and absolute address
+//! operands. Every read and every resolved address is checked against the
+//! module's sections, so a bad match can never send us outside the image.
+
+use crate::patches::pe::Module;
+
+/// Reasons an operand could not be resolved
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OperandError {
+    /// The instruction bytes at this address are outside the module's sections
+    OutOfBounds(usize),
+    /// The instruction at this address is not one the helper understands
+    UnexpectedOpcode { address: usize, opcode: u8 },
+    /// The decoded address points outside the module's sections
+    TargetOutsideModule(usize),
+}
+
+impl std::fmt::Display for OperandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OperandError::OutOfBounds(address) => {
+                write!(f, "0x{:08X} is outside the module", address)
+            }
+            OperandError::UnexpectedOpcode { address, opcode } => {
+                write!(f, "unexpected opcode 0x{:02X} at 0x{:08X}", opcode, address)
+            }
+            OperandError::TargetOutsideModule(target) => {
+                write!(f, "operand points outside the module (0x{:08X})", target)
+            }
+        }
+    }
+}
+
+/// Destination of the relative branch at `address`
+///
+/// Handles `call rel32` (E8), `jmp rel32` (E9), `jcc rel32` (0F 80-8F),
+/// `jmp rel8` (EB) and `jcc rel8` (70-7F).
+#[allow(dead_code)]
+pub fn branch_target(module: &Module, address: usize) -> Result<usize, OperandError> {
+    let opcode = read(module, address, 1)?[0];
+
+    let (operand, len) = match opcode {
+        0xE8 | 0xE9 => (1, 4),
+        0xEB | 0x70..=0x7F => (1, 1),
+        0x0F => match read(module, address + 1, 1)?[0] {
+            0x80..=0x8F => (2, 4),
+            second => {
+                return Err(OperandError::UnexpectedOpcode {
+                    address: address + 1,
+                    opcode: second,
+                });
+            }
+        },
+        _ => return Err(OperandError::UnexpectedOpcode { address, opcode }),
+    };
+
+    let bytes = read(module, address + operand, len)?;
+    let displacement = match len {
+        1 => bytes[0] as i8 as isize,
+        _ => i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as isize,
+    };
+
+    let target = (address + operand + len).wrapping_add_signed(displacement);
+    check_target(module, target)
+}
+
+/// Absolute address encoded in the instruction at `address`
+///
+/// Handles `push imm32` (68), `mov eax, [imm32]` (A1), `mov [imm32], eax` (A3),
+/// `mov reg, imm32` (B8-BF) and `mov reg, [imm32]` / `mov [imm32], reg` (8B/89
+/// with a disp32-only ModRM).
+#[allow(dead_code)]
+pub fn absolute_operand(module: &Module, address: usize) -> Result<usize, OperandError> {
+    let opcode = read(module, address, 1)?[0];
+
+    let operand = match opcode {
+        0x68 | 0xA1 | 0xA3 | 0xB8..=0xBF => 1,
+        0x8B | 0x89 => {
+            let modrm = read(module, address + 1, 1)?[0];
+            // mod = 00, rm = 101: [disp32]
+            if modrm & 0xC7 != 0x05 {
+                return Err(OperandError::UnexpectedOpcode {
+                    address: address + 1,
+                    opcode: modrm,
+                });
+            }
+            2
+        }
+        _ => return Err(OperandError::UnexpectedOpcode { address, opcode }),
+    };
+
+    let target = read_u32(module, address + operand)? as usize;
+    check_target(module, target)
+}
+
+/// Pointer stored at `address`, which must itself point into the module
+///
+/// Typically used on the result of [`absolute_operand`] to follow a global
+/// that holds a function or vtable pointer.
+#[allow(dead_code)]
+pub fn dereference(module: &Module, address: usize) -> Result<usize, OperandError> {
+    let value = read_u32(module, address)? as usize;
+    check_target(module, value)
+}
+
+/// Read a little-endian u32 from the module
+pub fn read_u32(module: &Module, address: usize) -> Result<u32, OperandError> {
+    let bytes = read(module, address, 4)?;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn read(module: &Module, address: usize, len: usize) -> Result<&[u8], OperandError> {
+    module
+        .read(address, len)
+        .ok_or(OperandError::OutOfBounds(address))
+}
+
+fn check_target(module: &Module, target: usize) -> Result<usize, OperandError> {
+    match module.read(target, 1) {
+        Some(_) => Ok(target),
+        None => Err(OperandError::TargetOutsideModule(target)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::patches::fixture::{self, CODE, DATA, IMAGE_BASE};
+
+    const TEXT: usize = IMAGE_BASE + 0x1000;
+    const GLOBALS: usize = IMAGE_BASE + 0x2000;
+
+    /// Code and data sections with `code` placed at the given offsets into .text
+    fn module(code: &[(usize, &[u8])]) -> Module {
+        let mut text = vec![0x90; 0x100];
+        for (offset, bytes) in code {
+            text[*offset..offset + bytes.len()].copy_from_slice(bytes);
+        }
+
+        let mut globals = vec![0; 0x10];
+        globals[..4].copy_from_slice(&(TEXT as u32 + 0x40).to_le_bytes());
+
+        fixture::module(
+            &[
+                (".text", 0x1000, text, CODE),
+                (".data", 0x2000, globals, DATA),
+            ],
+            &[],
+        )
+    }
+
+    fn imm32(value: usize) -> [u8; 4] {
+        (value as u32).to_le_bytes()
+    }
+
+    #[test]
+    fn relative_branches() {
+        let module = module(&[
+            (0x00, &[0xE8, 0x0B, 0x00, 0x00, 0x00]),
+            (0x10, &[0xE9, 0xEB, 0xFF, 0xFF, 0xFF]),
+            (0x20, &[0x0F, 0x84, 0x10, 0x00, 0x00, 0x00]),
+            (0x30, &[0xEB, 0xFE]),
+            (0x32, &[0x74, 0x05]),
+            (0x34, &[0x7F, 0x80]),
+        ]);
+
+        assert_eq!(branch_target(&module, TEXT), Ok(TEXT + 0x10));
+        assert_eq!(branch_target(&module, TEXT + 0x10), Ok(TEXT));
+        assert_eq!(branch_target(&module, TEXT + 0x20), Ok(TEXT + 0x36));
+        assert_eq!(branch_target(&module, TEXT + 0x30), Ok(TEXT + 0x30));
+        assert_eq!(branch_target(&module, TEXT + 0x32), Ok(TEXT + 0x39));
+        // rel8 is signed: 0x36 - 0x80
+        assert_eq!(
+            branch_target(&module, TEXT + 0x34),
+            Err(OperandError::TargetOutsideModule(TEXT + 0x36 - 0x80))
+        );
+    }
+
+    #[test]
+    fn branch_target_rejects_other_instructions() {
+        let module = module(&[(0x00, &[0x0F, 0x05]), (0x02, &[0xFF, 0x15])]);
+
+        assert_eq!(
+            branch_target(&module, TEXT),
+            Err(OperandError::UnexpectedOpcode {
+                address: TEXT + 1,
+                opcode: 0x05
+            })
+        );
+        assert_eq!(
+            branch_target(&module, TEXT + 2),
+            Err(OperandError::UnexpectedOpcode {
+                address: TEXT + 2,
+                opcode: 0xFF
+            })
+        );
+    }
+
+    #[test]
+    fn branch_target_stays_inside_the_module() {
+        let module = module(&[
+            (0x00, &[0xE8, 0x00, 0x00, 0x00, 0x80]),
+            (0xFD, &[0xE9, 0x00, 0x00]),
+        ]);
+
+        assert_eq!(
+            branch_target(&module, TEXT),
+            Err(OperandError::TargetOutsideModule(
+                (TEXT + 5).wrapping_sub(0x8000_0000)
+            ))
+        );
+        // The displacement runs past the end of the section
+        assert_eq!(
+            branch_target(&module, TEXT + 0xFD),
+            Err(OperandError::OutOfBounds(TEXT + 0xFE))
+        );
+        assert_eq!(
+            branch_target(&module, IMAGE_BASE),
+            Err(OperandError::OutOfBounds(IMAGE_BASE))
+        );
+    }
+
+    #[test]
+    fn absolute_operands() {
+        let global = imm32(GLOBALS + 4);
+        let push = [&[0x68][..], &global].concat();
+        let load_eax = [&[0xA1][..], &global].concat();
+        let store_eax = [&[0xA3][..], &global].concat();
+        let load_ecx_imm = [&[0xB9][..], &global].concat();
+        let load_edx = [&[0x8B, 0x15][..], &global].concat();
+        let store_esi = [&[0x89, 0x35][..], &global].concat();
+        let module = module(&[
+            (0x00, &push),
+            (0x08, &load_eax),
+            (0x10, &store_eax),
+            (0x18, &load_ecx_imm),
+            (0x20, &load_edx),
+            (0x28, &store_esi),
+        ]);
+
+        for offset in [0x00, 0x08, 0x10, 0x18, 0x20, 0x28] {
+            assert_eq!(
+                absolute_operand(&module, TEXT + offset),
+                Ok(GLOBALS + 4),
+                "at +0x{:X}",
+                offset
+            );
+        }
+    }
+
+    #[test]
+    fn absolute_operand_rejects_other_addressing() {
+        let outside = [&[0x68][..], &imm32(0x1234_5678)].concat();
+        let module = module(&[
+            (0x00, &[0x8B, 0x45, 0x08]),
+            (0x03, &[0x8B, 0xC5]),
+            (0x05, &[0xE8]),
+            (0x10, &outside),
+        ]);
+
+        assert_eq!(
+            absolute_operand(&module, TEXT),
+            Err(OperandError::UnexpectedOpcode {
+                address: TEXT + 1,
+                opcode: 0x45
+            })
+        );
+        assert_eq!(
+            absolute_operand(&module, TEXT + 3),
+            Err(OperandError::UnexpectedOpcode {
+                address: TEXT + 4,
+                opcode: 0xC5
+            })
+        );
+        assert_eq!(
+            absolute_operand(&module, TEXT + 5),
+            Err(OperandError::UnexpectedOpcode {
+                address: TEXT + 5,
+                opcode: 0xE8
+            })
+        );
+        assert_eq!(
+            absolute_operand(&module, TEXT + 0x10),
+            Err(OperandError::TargetOutsideModule(0x1234_5678))
+        );
+    }
+
+    #[test]
+    fn dereference_follows_pointers_into_the_module() {
+        let module = module(&[]);
+
+        assert_eq!(dereference(&module, GLOBALS), Ok(TEXT + 0x40));
+        assert_eq!(
+            dereference(&module, GLOBALS + 4),
+            Err(OperandError::TargetOutsideModule(0))
+        );
+        assert_eq!(
+            dereference(&module, GLOBALS + 0x0E),
+            Err(OperandError::OutOfBounds(GLOBALS + 0x0E))
+        );
+        assert_eq!(read_u32(&module, GLOBALS), Ok(TEXT as u32 + 0x40));
+    }
+}
@@ -0,0 +1,680 @@
+//! Signature pattern parsing
+//!
+//! Every pattern byte is a value plus a bit mask, and a byte matches when
+//! `data & mask == value & mask`. The text syntax accepts:
+//!
+//! - `8B` - exact byte
+//! - `??` or `?` - whole-byte wildcard (IDA style)
+//! - `8?` / `?B` - nibble wildcards
+//! - `C0&F8` - explicit bit mask, e.g. a ModRM byte with any register in the low bits
+//! - `{75,EB}` - any one of the listed bytes, e.g. an original and a patched opcode
+//! - `807E??00` - bytes written without separators (x64dbg style)
+//! - `??{95}` - a single-byte token repeated N times
+//! - `|` - the patch point, i.e. the offset patches are applied at
+//! - `[name:75 ??]` - a named capture over the enclosed bytes
+//!
+//! Code-style patterns (`"\x80\x7E\x00"` + `"xx?"`) are built with [`Pattern::from_code`].
+
+/// Largest `{N}` repeat count, far beyond any real signature
+const MAX_REPEAT: usize = 0x1000;
+
+/// A parsed signature pattern with per-bit wildcards
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pattern {
+    bytes: Vec<u8>,
+    mask: Vec<u8>, // set bits must match, clear bits are wildcards
+    /// Offsets of `{..}` bytes and the values allowed there
+    alternatives: Vec<(usize, Vec<u8>)>,
+    patch_point: Option<usize>,
+    captures: Vec<CaptureGroup>,
+}
+
+/// A named range of pattern bytes, e.g. `[jmp:??]`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaptureGroup {
+    pub name: String,
+    pub offset: usize,
+    pub len: usize,
+}
+
+/// Why a pattern string was rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    /// The pattern contains no bytes
+    Empty,
+    /// A character that is not a hex digit or `?`
+    InvalidChar(char),
+    /// A token ends in the middle of a byte
+    IncompleteByte,
+    /// A `value&mask` token is missing one side or has extra text
+    InvalidBitMask,
+    /// The byte string and mask string of a code-style pattern differ in length
+    MaskLength { bytes: usize, mask: usize },
+    /// A `{N}` suffix with a bad count, or on a token that is not exactly one byte
+    InvalidRepeat,
+    /// A `{..}` list that is empty, unclosed or has an entry that is not one exact byte
+    InvalidAlternatives,
+    /// More than one `|` marker
+    DuplicatePatchPoint,
+    /// A capture without a name, with a duplicate name, nested in another or closed twice
+    InvalidCapture,
+    /// A `[name:` capture that is never closed with `]`
+    UnclosedCapture,
+}
+
+/// A pattern parse failure and the byte offset in the pattern string where it was detected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseError {
+    pub position: usize,
+    pub kind: ParseErrorKind,
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.kind {
+            ParseErrorKind::Empty => write!(f, "empty pattern"),
+            ParseErrorKind::InvalidChar(c) => {
+                write!(f, "unexpected {:?} at position {}", c, self.position)
+            }
+            ParseErrorKind::IncompleteByte => {
+                write!(f, "incomplete byte at position {}", self.position)
+            }
+            ParseErrorKind::InvalidBitMask => {
+                write!(f, "malformed value&mask at position {}", self.position)
+            }
+            ParseErrorKind::MaskLength { bytes, mask } => write!(
+                f,
+                "mask has {} characters but there are {} bytes",
+                mask, bytes
+            ),
+            ParseErrorKind::InvalidRepeat => {
+                write!(f, "invalid repeat count at position {}", self.position)
+            }
+            ParseErrorKind::InvalidAlternatives => {
+                write!(f, "malformed byte list at position {}", self.position)
+            }
+            ParseErrorKind::DuplicatePatchPoint => {
+                write!(f, "second patch point at position {}", self.position)
+            }
+            ParseErrorKind::InvalidCapture => {
+                write!(f, "malformed capture at position {}", self.position)
+            }
+            ParseErrorKind::UnclosedCapture => {
+                write!(f, "capture at position {} is never closed", self.position)
+            }
+        }
+    }
+}
+
+impl Pattern {
+    /// Parse a pattern string like "80 79 ?? 4? 8B 54"
+    pub fn parse(pattern: &str) -> Result<Self, ParseError> {
+        let mut out = Self::empty();
+
+        // Name, start offset and text position of the capture currently open
+        let mut open: Option<(&str, usize, usize)> = None;
+
+        for (position, token) in tokens(pattern) {
+            let error = |kind| ParseError { position, kind };
+
+            if token == "|" {
+                if out.patch_point.is_some() {
+                    return Err(error(ParseErrorKind::DuplicatePatchPoint));
+                }
+                out.patch_point = Some(out.len());
+                continue;
+            }
+
+            let mut token = token;
+            let mut token_position = position;
+
+            if let Some(rest) = token.strip_prefix('[') {
+                let Some((name, rest)) = rest.split_once(':') else {
+                    return Err(error(ParseErrorKind::InvalidCapture));
+                };
+
+                let valid_name = !name.is_empty()
+                    && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+                    && !out.captures.iter().any(|c| c.name == name);
+                if open.is_some() || !valid_name {
+                    return Err(error(ParseErrorKind::InvalidCapture));
+                }
+
+                open = Some((name, out.len(), position));
+                token = rest;
+                token_position += name.len() + 2;
+            }
+
+            let close = match token.strip_suffix(']') {
+                Some(rest) => {
+                    token = rest;
+                    true
+                }
+                None => false,
+            };
+
+            if !token.is_empty() {
+                out.parse_token(token, token_position)?;
+            }
+
+            if close {
+                let Some((name, offset, _)) = open.take() else {
+                    return Err(error(ParseErrorKind::InvalidCapture));
+                };
+                if out.len() == offset {
+                    return Err(error(ParseErrorKind::InvalidCapture));
+                }
+
+                out.captures.push(CaptureGroup {
+                    name: name.to_string(),
+                    offset,
+                    len: out.len() - offset,
+                });
+            }
+        }
+
+        if let Some((_, _, position)) = open {
+            return Err(ParseError {
+                position,
+                kind: ParseErrorKind::UnclosedCapture,
+            });
+        }
+
+        if out.bytes.is_empty() {
+            return Err(ParseError {
+                position: 0,
+                kind: ParseErrorKind::Empty,
+            });
+        }
+
+        Ok(out)
+    }
+
+    /// Build a pattern from raw bytes and a code-style mask (`x` = match, `?` = wildcard)
+    #[allow(dead_code)]
+    pub fn from_code(bytes: &[u8], mask: &str) -> Result<Self, ParseError> {
+        if mask.len() != bytes.len() {
+            return Err(ParseError {
+                position: mask.len().min(bytes.len()),
+                kind: ParseErrorKind::MaskLength {
+                    bytes: bytes.len(),
+                    mask: mask.len(),
+                },
+            });
+        }
+
+        if bytes.is_empty() {
+            return Err(ParseError {
+                position: 0,
+                kind: ParseErrorKind::Empty,
+            });
+        }
+
+        let mut out = Self::empty();
+
+        for (position, (&byte, c)) in bytes.iter().zip(mask.chars()).enumerate() {
+            match c {
+                'x' | 'X' => out.push(byte, 0xFF),
+                '?' => out.push(0, 0),
+                _ => {
+                    return Err(ParseError {
+                        position,
+                        kind: ParseErrorKind::InvalidChar(c),
+                    });
+                }
+            }
+        }
+
+        Ok(out)
+    }
+
+    /// A pattern matching exactly `bytes`
+    pub fn exact(bytes: &[u8]) -> Self {
+        Self {
+            bytes: bytes.to_vec(),
+            mask: vec![0xFF; bytes.len()],
+            alternatives: Vec::new(),
+            patch_point: None,
+            captures: Vec::new(),
+        }
+    }
+
+    fn empty() -> Self {
+        Self {
+            bytes: Vec::new(),
+            mask: Vec::new(),
+            alternatives: Vec::new(),
+            patch_point: None,
+            captures: Vec::new(),
+        }
+    }
+
+    /// Parse one byte token, optionally followed by a `{N}` repeat count
+    fn parse_token(&mut self, token: &str, position: usize) -> Result<(), ParseError> {
+        if let Some(list) = token.strip_prefix('{') {
+            return self.parse_alternatives(list, position);
+        }
+
+        if let Some(body) = token.strip_suffix('}') {
+            let error = ParseError {
+                position,
+                kind: ParseErrorKind::InvalidRepeat,
+            };
+            let (body, count) = body.split_once('{').ok_or(error)?;
+            let count: usize = count.parse().map_err(|_| error)?;
+            if count > MAX_REPEAT {
+                return Err(error);
+            }
+
+            let start = self.len();
+            self.parse_token(body, position)?;
+            if count == 0 || self.len() != start + 1 {
+                return Err(error);
+            }
+
+            let (value, mask) = (self.bytes[start], self.mask[start]);
+            for _ in 1..count {
+                self.push(value, mask);
+            }
+            return Ok(());
+        }
+
+        if token == "?" {
+            self.push(0, 0);
+        } else if let Some((value, mask)) = token.split_once('&') {
+            let error = ParseError {
+                position,
+                kind: ParseErrorKind::InvalidBitMask,
+            };
+            let value = parse_hex_byte(value).ok_or(error)?;
+            let mask = parse_hex_byte(mask).ok_or(error)?;
+            self.push(value, mask);
+        } else {
+            self.parse_nibbles(token, position)?;
+        }
+
+        Ok(())
+    }
+
+    /// Parse the rest of a `{75,EB}` token
+    ///
+    /// The bits all values share go into the mask like any other byte, so the
+    /// list only needs checking where the mask already matches.
+    fn parse_alternatives(&mut self, list: &str, position: usize) -> Result<(), ParseError> {
+        let error = ParseError {
+            position,
+            kind: ParseErrorKind::InvalidAlternatives,
+        };
+        let mut values = list
+            .strip_suffix('}')
+            .ok_or(error)?
+            .split(',')
+            .map(parse_hex_byte)
+            .collect::<Option<Vec<u8>>>()
+            .ok_or(error)?;
+        values.sort_unstable();
+        values.dedup();
+
+        let first = values[0];
+        let mask = values
+            .iter()
+            .fold(0xFF, |mask, &value| mask & !(value ^ first));
+        if values.len() > 1 {
+            self.alternatives.push((self.len(), values));
+        }
+        self.push(first, mask);
+        Ok(())
+    }
+
+    /// Parse a run of nibble characters (`0-9`, `A-F`, `?`), two per byte
+    fn parse_nibbles(&mut self, token: &str, position: usize) -> Result<(), ParseError> {
+        let mut chars = token.char_indices();
+
+        while let Some((offset, high)) = chars.next() {
+            let Some((_, low)) = chars.next() else {
+                return Err(ParseError {
+                    position: position + offset,
+                    kind: ParseErrorKind::IncompleteByte,
+                });
+            };
+
+            let (high_value, high_mask) = parse_nibble(high).ok_or(ParseError {
+                position: position + offset,
+                kind: ParseErrorKind::InvalidChar(high),
+            })?;
+            let (low_value, low_mask) = parse_nibble(low).ok_or(ParseError {
+                position: position + offset + 1,
+                kind: ParseErrorKind::InvalidChar(low),
+            })?;
+
+            self.push((high_value << 4) | low_value, (high_mask << 4) | low_mask);
+        }
+
+        Ok(())
+    }
+
+    fn push(&mut self, value: u8, mask: u8) {
+        self.bytes.push(value & mask);
+        self.mask.push(mask);
+    }
+
+    /// Check if pattern matches at the start of `data`
+    #[inline]
+    pub fn matches(&self, data: &[u8]) -> bool {
+        data.len() >= self.len()
+            && self
+                .bytes
+                .iter()
+                .zip(&self.mask)
+                .zip(data)
+                .all(|((&value, &mask), &byte)| byte & mask == value)
+            && self
+                .alternatives
+                .iter()
+                .all(|(offset, values)| values.contains(&data[*offset]))
+    }
+
+    /// Get the length of the pattern
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// Offset of the `|` marker, or 0 if the pattern has none
+    pub fn patch_point(&self) -> usize {
+        self.patch_point.unwrap_or(0)
+    }
+
+    /// Named captures, in the order they appear in the pattern
+    pub fn captures(&self) -> &[CaptureGroup] {
+        &self.captures
+    }
+
+    /// Offset and value of the fully fixed byte least likely to occur in ordinary code or data
+    ///
+    /// Returns None if no byte of the pattern is fully fixed.
+    pub fn anchor(&self) -> Option<(usize, u8)> {
+        self.mask
+            .iter()
+            .zip(&self.bytes)
+            .enumerate()
+            .filter(|(_, (mask, _))| **mask == 0xFF)
+            .min_by_key(|(_, (_, value))| byte_frequency(**value))
+            .map(|(i, (_, value))| (i, *value))
+    }
+}
+
+/// Format bytes as exact pattern tokens ("68 00 20 40 00"), for building patterns at runtime
+pub fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Split a pattern into whitespace-separated tokens with their byte offsets
+fn tokens(pattern: &str) -> impl Iterator<Item = (usize, &str)> {
+    pattern
+        .split_whitespace()
+        .map(move |token| (token.as_ptr() as usize - pattern.as_ptr() as usize, token))
+}
+
+/// Parse one nibble character into (value, mask)
+fn parse_nibble(c: char) -> Option<(u8, u8)> {
+    if c == '?' {
+        return Some((0, 0));
+    }
+
+    c.to_digit(16).map(|digit| (digit as u8, 0xF))
+}
+
+/// Parse exactly two hex digits
+fn parse_hex_byte(text: &str) -> Option<u8> {
+    if text.len() != 2 || !text.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    u8::from_str_radix(text, 16).ok()
+}
+
+/// Rough frequency class of a byte in x86 code and string data (higher = more common)
+///
+/// Used to pick anchor bytes, so only the relative order matters.
+fn byte_frequency(byte: u8) -> u8 {
+    match byte {
+        // Padding, zero fill and all-ones immediates
+        0x00 | 0xFF | 0xCC | 0x90 => 4,
+        // mov/lea/test/call/jcc opcodes and the ModRM/SIB bytes of [esp+disp8]
+        0x8B
+        | 0x89
+        | 0x8D
+        | 0x85
+        | 0xE8
+        | 0x0F
+        | 0x83
+        | 0x24
+        | 0x44
+        | 0x4C
+        | 0x54
+        | 0x74
+        | 0x75
+        | 0xC7
+        | 0xC3
+        | 0x33
+        | 0x50..=0x57 => 3,
+        // Lowercase ASCII, common in string literals
+        b'a'..=b'z' | b' ' | b'.' | b'_' => 2,
+        _ => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parse and return (bytes, mask), with the values already masked
+    fn parse(pattern: &str) -> (Vec<u8>, Vec<u8>) {
+        let pattern = Pattern::parse(pattern).unwrap();
+        (pattern.bytes, pattern.mask)
+    }
+
+    fn error(pattern: &str) -> (usize, ParseErrorKind) {
+        let error = Pattern::parse(pattern).unwrap_err();
+        (error.position, error.kind)
+    }
+
+    #[test]
+    fn exact_and_wildcard_bytes() {
+        assert_eq!(
+            parse("80 79 ?? ? 8b"),
+            (vec![0x80, 0x79, 0, 0, 0x8B], vec![0xFF, 0xFF, 0, 0, 0xFF])
+        );
+    }
+
+    #[test]
+    fn nibble_wildcards() {
+        assert_eq!(parse("4? ?B"), (vec![0x40, 0x0B], vec![0xF0, 0x0F]));
+        assert_eq!(parse("?? ?"), (vec![0, 0], vec![0, 0]));
+    }
+
+    #[test]
+    fn bytes_without_separators() {
+        assert_eq!(parse("807E??00"), parse("80 7E ?? 00"));
+        assert_eq!(
+            parse("8??B 00"),
+            (vec![0x80, 0x0B, 0], vec![0xF0, 0x0F, 0xFF])
+        );
+    }
+
+    #[test]
+    fn bit_masks() {
+        assert_eq!(parse("C0&F8"), (vec![0xC0], vec![0xF8]));
+        // Bits outside the mask are dropped from the value
+        assert_eq!(parse("FF&0F 8B"), (vec![0x0F, 0x8B], vec![0x0F, 0xFF]));
+
+        let pattern = Pattern::parse("C0&F8").unwrap();
+        assert!(pattern.matches(&[0xC7]));
+        assert!(!pattern.matches(&[0xC8]));
+    }
+
+    #[test]
+    fn alternatives() {
+        // The mask keeps the bits 75 and EB share
+        assert_eq!(parse("8B {75,EB}"), (vec![0x8B, 0x61], vec![0xFF, 0x61]));
+        assert_eq!(parse("{EB,75,EB}"), parse("{75,EB}"));
+        assert_eq!(parse("{75}"), parse("75"));
+
+        let pattern = Pattern::parse("8B [jmp:{75,EB}] | {5F,2E}").unwrap();
+        assert!(pattern.matches(&[0x8B, 0x75, 0x5F]));
+        assert!(pattern.matches(&[0x8B, 0xEB, 0x2E]));
+        // Share the masked bits with both, but are neither
+        assert!(!pattern.matches(&[0x8B, 0x61, 0x5F]));
+        assert!(!pattern.matches(&[0x8B, 0xFF, 0x5F]));
+        assert!(!pattern.matches(&[0x8B, 0x75, 0x0E]));
+        assert!(!pattern.matches(&[0x8B, 0x75]));
+        assert_eq!(pattern.patch_point(), 2);
+        assert_eq!(pattern.captures()[0].offset, 1);
+        assert_eq!(pattern.anchor(), Some((0, 0x8B)));
+    }
+
+    #[test]
+    fn repeats() {
+        assert_eq!(
+            parse("??{3} 8B"),
+            (vec![0, 0, 0, 0x8B], vec![0, 0, 0, 0xFF])
+        );
+        assert_eq!(parse("90{2}"), (vec![0x90; 2], vec![0xFF; 2]));
+        assert_eq!(parse("C0&F8{2}"), (vec![0xC0; 2], vec![0xF8; 2]));
+        assert_eq!(parse("4?{1}"), parse("4?"));
+    }
+
+    #[test]
+    fn patch_point() {
+        let pattern = Pattern::parse("75 ?? | 8B 45").unwrap();
+        assert_eq!(pattern.len(), 4);
+        assert_eq!(pattern.patch_point(), 2);
+
+        assert_eq!(Pattern::parse("8B 45").unwrap().patch_point(), 0);
+        assert_eq!(Pattern::parse("8B 45 |").unwrap().patch_point(), 2);
+    }
+
+    #[test]
+    fn captures() {
+        let pattern = Pattern::parse("80 7E [flag:??] 00 [jmp:75 ??] | [x:C3]").unwrap();
+        let captures: Vec<_> = pattern
+            .captures()
+            .iter()
+            .map(|c| (c.name.as_str(), c.offset, c.len))
+            .collect();
+
+        assert_eq!(captures, [("flag", 2, 1), ("jmp", 4, 2), ("x", 6, 1)]);
+        assert_eq!(pattern.patch_point(), 6);
+        assert_eq!(pattern.len(), 7);
+
+        let pattern = Pattern::parse("[rel:??{4}] [b:8B45]").unwrap();
+        assert_eq!(pattern.captures()[0].len, 4);
+        assert_eq!(
+            (pattern.captures()[1].offset, pattern.captures()[1].len),
+            (4, 2)
+        );
+    }
+
+    #[test]
+    fn matches_only_full_length_data() {
+        let pattern = Pattern::parse("8B ?? 74").unwrap();
+
+        assert!(pattern.matches(&[0x8B, 0x00, 0x74]));
+        assert!(pattern.matches(&[0x8B, 0xFF, 0x74, 0x00]));
+        assert!(!pattern.matches(&[0x8B, 0xFF, 0x75]));
+        assert!(!pattern.matches(&[0x8B, 0xFF]));
+    }
+
+    #[test]
+    fn anchor_is_the_rarest_fixed_byte() {
+        assert_eq!(
+            Pattern::parse("8B 45 ?? 74").unwrap().anchor(),
+            Some((1, 0x45))
+        );
+        assert_eq!(Pattern::parse("00 8B").unwrap().anchor(), Some((1, 0x8B)));
+        assert_eq!(Pattern::parse("?? 4? C0&F8").unwrap().anchor(), None);
+    }
+
+    #[test]
+    fn errors_report_their_position() {
+        assert_eq!(error(""), (0, ParseErrorKind::Empty));
+        assert_eq!(error("  | "), (0, ParseErrorKind::Empty));
+        assert_eq!(error("8B 4G"), (4, ParseErrorKind::InvalidChar('G')));
+        assert_eq!(error("8B XY"), (3, ParseErrorKind::InvalidChar('X')));
+        assert_eq!(error("8B 807"), (5, ParseErrorKind::IncompleteByte));
+        assert_eq!(error("8B 4"), (3, ParseErrorKind::IncompleteByte));
+        assert_eq!(error("8B C0&F"), (3, ParseErrorKind::InvalidBitMask));
+        assert_eq!(error("8B &F8"), (3, ParseErrorKind::InvalidBitMask));
+        assert_eq!(error("8B C0&F8&00"), (3, ParseErrorKind::InvalidBitMask));
+        assert_eq!(error("8B ??{0}"), (3, ParseErrorKind::InvalidRepeat));
+        assert_eq!(error("8B ??{x}"), (3, ParseErrorKind::InvalidRepeat));
+        assert_eq!(error("8B 8B45{2}"), (3, ParseErrorKind::InvalidRepeat));
+        assert_eq!(error("8B ??{2"), (5, ParseErrorKind::InvalidChar('{')));
+        assert_eq!(error("8B ??{99999}"), (3, ParseErrorKind::InvalidRepeat));
+        assert_eq!(error("8B {75,EB"), (3, ParseErrorKind::InvalidAlternatives));
+        assert_eq!(error("8B {}"), (3, ParseErrorKind::InvalidAlternatives));
+        assert_eq!(error("8B {75,}"), (3, ParseErrorKind::InvalidAlternatives));
+        assert_eq!(
+            error("8B {75,E?}"),
+            (3, ParseErrorKind::InvalidAlternatives)
+        );
+        assert_eq!(
+            error("8B {75,EB}{2}"),
+            (3, ParseErrorKind::InvalidAlternatives)
+        );
+        assert_eq!(error("8B {3}"), (3, ParseErrorKind::InvalidAlternatives));
+        assert_eq!(error("8B | 45 |"), (8, ParseErrorKind::DuplicatePatchPoint));
+        assert_eq!(error("[8B]"), (0, ParseErrorKind::InvalidCapture));
+        assert_eq!(error("8B [:45]"), (3, ParseErrorKind::InvalidCapture));
+        assert_eq!(error("8B [a-b:45]"), (3, ParseErrorKind::InvalidCapture));
+        assert_eq!(error("[a:8B] [a:45]"), (7, ParseErrorKind::InvalidCapture));
+        assert_eq!(error("[a:8B [b:45]]"), (6, ParseErrorKind::InvalidCapture));
+        assert_eq!(error("[a:] 8B"), (0, ParseErrorKind::InvalidCapture));
+        assert_eq!(error("8B 45]"), (3, ParseErrorKind::InvalidCapture));
+        assert_eq!(error("8B [a:45 ??"), (3, ParseErrorKind::UnclosedCapture));
+        // Inside a capture, positions still point into the pattern string
+        assert_eq!(
+            error("8B [name:4G]"),
+            (10, ParseErrorKind::InvalidChar('G'))
+        );
+        // Non-ASCII input is reported, not split mid-character
+        assert_eq!(error("8B é0"), (3, ParseErrorKind::InvalidChar('é')));
+    }
+
+    #[test]
+    fn error_messages() {
+        let message = |pattern| Pattern::parse(pattern).unwrap_err().to_string();
+
+        assert_eq!(message(""), "empty pattern");
+        assert_eq!(message("8B 4G"), "unexpected 'G' at position 4");
+        assert_eq!(message("8B [a:45"), "capture at position 3 is never closed");
+    }
+
+    #[test]
+    fn code_style_patterns() {
+        let pattern = Pattern::from_code(b"\x80\x7E\x05\x00", "xx?x").unwrap();
+        assert_eq!(pattern, Pattern::parse("80 7E ?? 00").unwrap());
+
+        let error = Pattern::from_code(b"\x80\x7E", "x").unwrap_err();
+        assert_eq!(error.kind, ParseErrorKind::MaskLength { bytes: 2, mask: 1 });
+        assert_eq!(
+            Pattern::from_code(b"\x80", "y").unwrap_err().kind,
+            ParseErrorKind::InvalidChar('y')
+        );
+        assert_eq!(
+            Pattern::from_code(b"", "").unwrap_err().kind,
+            ParseErrorKind::Empty
+        );
+    }
+
+    #[test]
+    fn hex_round_trips() {
+        let bytes = [0x68, 0x00, 0x20, 0x40, 0xFF];
+        assert_eq!(hex(&bytes), "68 00 20 40 FF");
+        assert_eq!(parse(&hex(&bytes)), (bytes.to_vec(), vec![0xFF; 5]));
+    }
+}
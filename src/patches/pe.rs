@@ -0,0 +1,786 @@
+//! Minimal PE header parsing
+//!
+//! Reads the DOS header, NT headers and section table of a 32-bit image.
+//! Parsing works on any byte slice that starts with the image headers, so it
+//! does not depend on the image actually being loaded. A [`Module`] is either a
+//! module loaded in this process or a file image mapped into a buffer, and the
+//! scanning code works the same on both.
+
+/// "MZ"
+const IMAGE_DOS_SIGNATURE: u16 = 0x5A4D;
+/// "PE\0\0"
+const IMAGE_NT_SIGNATURE: u32 = 0x0000_4550;
+/// PE32 optional header magic
+const IMAGE_NT_OPTIONAL_HDR32_MAGIC: u16 = 0x010B;
+
+/// Size of IMAGE_FILE_HEADER
+const FILE_HEADER_SIZE: usize = 20;
+/// Size of IMAGE_SECTION_HEADER
+const SECTION_HEADER_SIZE: usize = 40;
+
+/// Index of the import table in the data directory
+pub const IMAGE_DIRECTORY_ENTRY_IMPORT: usize = 1;
+/// Index of the base relocation table in the data directory
+pub const IMAGE_DIRECTORY_ENTRY_BASERELOC: usize = 5;
+
+/// Relocation type that adds the full 32-bit delta
+const IMAGE_REL_BASED_HIGHLOW: u16 = 3;
+
+/// Size of IMAGE_IMPORT_DESCRIPTOR
+const IMPORT_DESCRIPTOR_SIZE: usize = 20;
+/// Set in a 32-bit thunk that imports by ordinal
+const IMAGE_ORDINAL_FLAG32: u32 = 0x8000_0000;
+
+/// Section can be executed
+pub const IMAGE_SCN_MEM_EXECUTE: u32 = 0x2000_0000;
+/// Section can be read
+pub const IMAGE_SCN_MEM_READ: u32 = 0x4000_0000;
+
+/// Reasons a buffer could not be parsed as a PE image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeError {
+    /// A header or table extends past the end of the buffer
+    Truncated,
+    /// Missing "MZ" signature
+    BadDosSignature,
+    /// Missing "PE\0\0" signature
+    BadNtSignature,
+    /// Optional header is not PE32 (e.g. a 64-bit image)
+    UnsupportedMagic(u16),
+}
+
+impl std::fmt::Display for PeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PeError::Truncated => write!(f, "headers truncated"),
+            PeError::BadDosSignature => write!(f, "missing MZ signature"),
+            PeError::BadNtSignature => write!(f, "missing PE signature"),
+            PeError::UnsupportedMagic(magic) => {
+                write!(f, "unsupported optional header magic 0x{:04X}", magic)
+            }
+        }
+    }
+}
+
+/// A single entry of the section table
+#[derive(Debug, Clone)]
+pub struct Section {
+    pub name: [u8; 8],
+    pub virtual_address: u32,
+    pub virtual_size: u32,
+    pub raw_offset: u32,
+    pub raw_size: u32,
+    pub characteristics: u32,
+}
+
+impl Section {
+    /// Section name with trailing NUL padding removed
+    pub fn name(&self) -> &str {
+        let len = self.name.iter().position(|&b| b == 0).unwrap_or(8);
+        std::str::from_utf8(&self.name[..len]).unwrap_or("")
+    }
+
+    /// Size of the section once mapped into memory
+    ///
+    /// Some linkers leave `VirtualSize` as zero, in which case the raw size is used.
+    pub fn mapped_size(&self) -> u32 {
+        if self.virtual_size == 0 {
+            self.raw_size
+        } else {
+            self.virtual_size
+        }
+    }
+
+    pub fn is_executable(&self) -> bool {
+        self.characteristics & IMAGE_SCN_MEM_EXECUTE != 0
+    }
+
+    pub fn is_readable(&self) -> bool {
+        self.characteristics & IMAGE_SCN_MEM_READ != 0
+    }
+}
+
+/// An entry of the optional header's data directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DataDirectory {
+    pub virtual_address: u32,
+    pub size: u32,
+}
+
+/// The parts of the PE headers we care about
+#[derive(Debug, Clone)]
+pub struct PeHeaders {
+    /// Link time, as a Unix timestamp
+    pub time_date_stamp: u32,
+    /// Optional header checksum (0 if the linker did not set one)
+    pub checksum: u32,
+    pub image_base: u32,
+    pub size_of_image: u32,
+    pub size_of_headers: u32,
+    pub data_directories: Vec<DataDirectory>,
+    pub sections: Vec<Section>,
+}
+
+impl PeHeaders {
+    /// Parse headers from a buffer that starts at the image base (or file offset 0)
+    pub fn parse(data: &[u8]) -> Result<Self, PeError> {
+        if read_u16(data, 0)? != IMAGE_DOS_SIGNATURE {
+            return Err(PeError::BadDosSignature);
+        }
+
+        // e_lfanew
+        let nt = read_u32(data, 0x3C)? as usize;
+        if read_u32(data, nt)? != IMAGE_NT_SIGNATURE {
+            return Err(PeError::BadNtSignature);
+        }
+
+        let file_header = nt + 4;
+        let number_of_sections = read_u16(data, file_header + 2)? as usize;
+        let time_date_stamp = read_u32(data, file_header + 4)?;
+        let size_of_optional_header = read_u16(data, file_header + 16)? as usize;
+
+        let optional = file_header + FILE_HEADER_SIZE;
+        let magic = read_u16(data, optional)?;
+        if magic != IMAGE_NT_OPTIONAL_HDR32_MAGIC {
+            return Err(PeError::UnsupportedMagic(magic));
+        }
+
+        let image_base = read_u32(data, optional + 28)?;
+        let size_of_image = read_u32(data, optional + 56)?;
+        let size_of_headers = read_u32(data, optional + 60)?;
+        let checksum = read_u32(data, optional + 64)?;
+
+        let number_of_rva_and_sizes = read_u32(data, optional + 92)? as usize;
+        let mut data_directories = Vec::with_capacity(number_of_rva_and_sizes.min(16));
+        for i in 0..number_of_rva_and_sizes.min(16) {
+            let entry = optional + 96 + i * 8;
+            data_directories.push(DataDirectory {
+                virtual_address: read_u32(data, entry)?,
+                size: read_u32(data, entry + 4)?,
+            });
+        }
+
+        let table = optional + size_of_optional_header;
+        let mut sections = Vec::with_capacity(number_of_sections);
+        for i in 0..number_of_sections {
+            let entry = table + i * SECTION_HEADER_SIZE;
+            let name = data
+                .get(entry..entry + 8)
+                .ok_or(PeError::Truncated)?
+                .try_into()
+                .map_err(|_| PeError::Truncated)?;
+
+            sections.push(Section {
+                name,
+                virtual_size: read_u32(data, entry + 8)?,
+                virtual_address: read_u32(data, entry + 12)?,
+                raw_size: read_u32(data, entry + 16)?,
+                raw_offset: read_u32(data, entry + 20)?,
+                characteristics: read_u32(data, entry + 36)?,
+            });
+        }
+
+        Ok(Self {
+            time_date_stamp,
+            checksum,
+            image_base,
+            size_of_image,
+            size_of_headers,
+            data_directories,
+            sections,
+        })
+    }
+
+    /// The data directory entry at `index`, if present and non-empty
+    pub fn data_directory(&self, index: usize) -> Option<DataDirectory> {
+        self.data_directories
+            .get(index)
+            .copied()
+            .filter(|directory| directory.virtual_address != 0 && directory.size != 0)
+    }
+
+    /// File offset of the byte at `rva`, if it is backed by raw section data
+    pub fn file_offset(&self, rva: usize) -> Option<usize> {
+        self.sections.iter().find_map(|section| {
+            let offset = rva.checked_sub(section.virtual_address as usize)?;
+            (offset < section.raw_size.min(section.mapped_size()) as usize)
+                .then(|| section.raw_offset as usize + offset)
+        })
+    }
+}
+
+/// How an imported function is identified
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ImportName {
+    Name(String),
+    Ordinal(u16),
+}
+
+/// A function imported by a module
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Import {
+    /// The DLL as named in the import table (case is not normalised)
+    pub dll: String,
+    pub function: ImportName,
+    /// Address of the function's import address table (IAT) slot
+    pub slot: usize,
+}
+
+/// A PE image, either loaded in this process or mapped from a file
+pub struct Module {
+    pub base: usize,
+    pub headers: PeHeaders,
+    /// The mapped image for modules built from a file; None for loaded modules
+    mapped: Option<Vec<u8>>,
+}
+
+impl Module {
+    /// Parse the headers of a module loaded at `base`
+    ///
+    /// # Safety
+    /// `base` must be the base address of a module mapped by the loader, so that
+    /// at least the first page (the headers) is readable.
+    pub unsafe fn from_base(base: usize) -> Result<Self, PeError> {
+        // The headers always fit in the first page of a mapped image
+        let headers = unsafe { std::slice::from_raw_parts(base as *const u8, 0x1000) };
+        let headers = PeHeaders::parse(headers)?;
+        Ok(Self {
+            base,
+            headers,
+            mapped: None,
+        })
+    }
+
+    /// Map a PE file the way the loader would, at its preferred image base
+    ///
+    /// Each section's raw data is copied to its RVA, so addresses found by
+    /// scanning are the ones the image would have if loaded at `ImageBase`.
+    /// Relocations are not applied, which is what we want for comparing
+    /// against code that was linked for that base.
+    pub fn from_file(file: &[u8]) -> Result<Self, PeError> {
+        let headers = PeHeaders::parse(file)?;
+        let mut image = vec![0u8; headers.size_of_image as usize];
+
+        let header_len = (headers.size_of_headers as usize)
+            .min(file.len())
+            .min(image.len());
+        image[..header_len].copy_from_slice(&file[..header_len]);
+
+        for section in &headers.sections {
+            let start = section.virtual_address as usize;
+            let len = section.raw_size.min(section.mapped_size()) as usize;
+            let raw = section.raw_offset as usize;
+
+            let source = file.get(raw..raw + len).ok_or(PeError::Truncated)?;
+            image
+                .get_mut(start..start + len)
+                .ok_or(PeError::Truncated)?
+                .copy_from_slice(source);
+        }
+
+        Ok(Self {
+            base: headers.image_base as usize,
+            headers,
+            mapped: Some(image),
+        })
+    }
+
+    /// The mapped bytes of a section, clamped to `SizeOfImage`
+    pub fn section_data(&self, section: &Section) -> &[u8] {
+        let start = section.virtual_address as usize;
+        let image_size = self.headers.size_of_image as usize;
+        if start >= image_size {
+            return &[];
+        }
+
+        let len = (section.mapped_size() as usize).min(image_size - start);
+
+        match &self.mapped {
+            Some(image) => &image[start..start + len],
+            // SAFETY: every section inside SizeOfImage is mapped for as long as the module is loaded
+            None => unsafe { std::slice::from_raw_parts((self.base + start) as *const u8, len) },
+        }
+    }
+
+    /// The section containing `address`
+    pub fn section_at(&self, address: usize) -> Option<&Section> {
+        let rva = address.checked_sub(self.base)?;
+        self.headers.sections.iter().find(|section| {
+            let start = section.virtual_address as usize;
+            rva >= start && rva - start < section.mapped_size() as usize
+        })
+    }
+
+    /// Read `len` bytes at `address`, if they lie entirely within one section
+    pub fn read(&self, address: usize, len: usize) -> Option<&[u8]> {
+        let rva = address.checked_sub(self.base)?;
+        self.headers.sections.iter().find_map(|section| {
+            let offset = rva.checked_sub(section.virtual_address as usize)?;
+            self.section_data(section)
+                .get(offset..offset.checked_add(len)?)
+        })
+    }
+
+    /// Addresses of the 32-bit values the loader relocates
+    ///
+    /// These are the absolute addresses embedded in code and data, which change
+    /// whenever the image is rebased. Returns them in ascending order.
+    pub fn relocations(&self) -> Vec<usize> {
+        let Some(directory) = self.headers.data_directory(IMAGE_DIRECTORY_ENTRY_BASERELOC) else {
+            return Vec::new();
+        };
+
+        let mut fixups = Vec::new();
+        let mut block = self.base + directory.virtual_address as usize;
+        let end = block + directory.size as usize;
+
+        // Each block is { page rva, block size, u16 entries[] } with type:4 | offset:12
+        while block + 8 <= end {
+            let Some(header) = self.read(block, 8) else {
+                break;
+            };
+            let page = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+            let size = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+            if size < 8 {
+                break;
+            }
+
+            let entries = self.read(block + 8, size - 8).unwrap_or(&[]);
+            for &entry in entries.as_chunks::<2>().0 {
+                let entry = u16::from_le_bytes(entry);
+                if entry >> 12 == IMAGE_REL_BASED_HIGHLOW {
+                    fixups.push(self.base + page + (entry & 0xFFF) as usize);
+                }
+            }
+
+            block += size;
+        }
+
+        fixups.sort_unstable();
+        fixups
+    }
+
+    /// Move a file image to `base` and apply its relocations, as the loader would
+    #[cfg(test)]
+    pub fn rebase(&mut self, base: usize) {
+        let delta = base.wrapping_sub(self.base) as u32;
+        let fixups = self.relocations();
+        let old_base = self.base;
+        let image = self
+            .mapped
+            .as_mut()
+            .expect("only file images can be rebased");
+
+        for fixup in fixups {
+            let value = &mut image[fixup - old_base..fixup - old_base + 4];
+            let relocated =
+                u32::from_le_bytes([value[0], value[1], value[2], value[3]]).wrapping_add(delta);
+            value.copy_from_slice(&relocated.to_le_bytes());
+        }
+        self.base = base;
+    }
+
+    /// Every function in the import table, in table order
+    ///
+    /// Names are read from the import name table, so this works both before
+    /// binding (a file) and after the loader has overwritten the IAT with
+    /// function addresses (a loaded module). Malformed entries end the walk.
+    pub fn imports(&self) -> Vec<Import> {
+        let Some(directory) = self.headers.data_directory(IMAGE_DIRECTORY_ENTRY_IMPORT) else {
+            return Vec::new();
+        };
+
+        let mut imports = Vec::new();
+        let mut descriptor = self.base + directory.virtual_address as usize;
+
+        // Each descriptor is { name table, timestamp, forwarder chain, dll name, address table }
+        while let Some(entry) = self.read(descriptor, IMPORT_DESCRIPTOR_SIZE) {
+            let field = |index: usize| {
+                let bytes = &entry[index * 4..index * 4 + 4];
+                u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize
+            };
+            let (names, dll, slots) = (field(0), field(3), field(4));
+            if dll == 0 || slots == 0 {
+                break;
+            }
+            let Some(dll) = self.read_str(self.base + dll) else {
+                break;
+            };
+
+            // Old linkers leave out the name table; the IAT then holds the names until bound
+            let names = if names != 0 { names } else { slots };
+            let mut index = 0;
+            while let Some(thunk) = self.read_u32(self.base + names + index * 4) {
+                if thunk == 0 {
+                    break;
+                }
+
+                let function = if thunk & IMAGE_ORDINAL_FLAG32 != 0 {
+                    ImportName::Ordinal(thunk as u16)
+                } else {
+                    // IMAGE_IMPORT_BY_NAME is { u16 hint, name }
+                    match self.read_str(self.base + thunk as usize + 2) {
+                        Some(name) => ImportName::Name(name.to_string()),
+                        None => break,
+                    }
+                };
+
+                imports.push(Import {
+                    dll: dll.to_string(),
+                    function,
+                    slot: self.base + slots + index * 4,
+                });
+                index += 1;
+            }
+
+            descriptor += IMPORT_DESCRIPTOR_SIZE;
+        }
+
+        imports
+    }
+
+    /// The IAT slot through which this module calls `dll!function`
+    ///
+    /// DLL names are compared without regard to case, function names exactly.
+    pub fn import_slot(&self, dll: &str, function: &str) -> Option<usize> {
+        self.imports()
+            .into_iter()
+            .find(|import| {
+                import.dll.eq_ignore_ascii_case(dll)
+                    && import.function == ImportName::Name(function.to_string())
+            })
+            .map(|import| import.slot)
+    }
+
+    fn read_u32(&self, address: usize) -> Option<u32> {
+        let bytes = self.read(address, 4)?;
+        Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// The NUL-terminated string at `address`, which must end within its section
+    fn read_str(&self, address: usize) -> Option<&str> {
+        let section = self.section_at(address)?;
+        let offset = address - self.base - section.virtual_address as usize;
+        let data = self.section_data(section).get(offset..)?;
+        let len = data.iter().position(|&b| b == 0)?;
+        std::str::from_utf8(&data[..len]).ok()
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, PeError> {
+    data.get(offset..offset.checked_add(2).ok_or(PeError::Truncated)?)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or(PeError::Truncated)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, PeError> {
+    data.get(offset..offset.checked_add(4).ok_or(PeError::Truncated)?)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or(PeError::Truncated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::patches::fixture::{self, CODE, DATA, IMAGE_BASE, NT, OPTIONAL, SECTION_TABLE};
+
+    fn two_sections() -> Vec<u8> {
+        fixture::file(
+            &[
+                (".text", 0x1000, vec![0xCC; 0x300], CODE),
+                (".data", 0x2000, vec![0x11; 0x80], DATA),
+            ],
+            &[(IMAGE_DIRECTORY_ENTRY_BASERELOC, 0x2000, 0x10)],
+        )
+    }
+
+    #[test]
+    fn parses_headers_and_section_table() {
+        let headers = PeHeaders::parse(&two_sections()).unwrap();
+
+        assert_eq!(headers.image_base, IMAGE_BASE as u32);
+        assert_eq!(headers.size_of_image, 0x3000);
+        assert_eq!(headers.size_of_headers, 0x400);
+        assert_eq!(headers.data_directories.len(), 16);
+        assert_eq!(
+            headers.data_directory(IMAGE_DIRECTORY_ENTRY_BASERELOC),
+            Some(DataDirectory {
+                virtual_address: 0x2000,
+                size: 0x10
+            })
+        );
+        assert_eq!(headers.data_directory(IMAGE_DIRECTORY_ENTRY_IMPORT), None);
+
+        let [text, data] = &headers.sections[..] else {
+            panic!("expected two sections, got {:?}", headers.sections);
+        };
+        assert_eq!(text.name(), ".text");
+        assert_eq!(
+            (
+                text.virtual_address,
+                text.virtual_size,
+                text.raw_offset,
+                text.raw_size
+            ),
+            (0x1000, 0x300, 0x400, 0x400)
+        );
+        assert!(text.is_executable() && text.is_readable());
+        assert_eq!(data.name(), ".data");
+        assert_eq!((data.virtual_address, data.raw_offset), (0x2000, 0x800));
+        assert!(!data.is_executable());
+    }
+
+    #[test]
+    fn mapped_size_falls_back_to_raw_size() {
+        let mut file = two_sections();
+        fixture::put_u32(&mut file, SECTION_TABLE + 8, 0);
+        let headers = PeHeaders::parse(&file).unwrap();
+
+        assert_eq!(headers.sections[0].mapped_size(), 0x400);
+    }
+
+    #[test]
+    fn rejects_truncated_headers() {
+        let file = two_sections();
+
+        assert_eq!(PeHeaders::parse(&[]).unwrap_err(), PeError::Truncated);
+        assert_eq!(
+            PeHeaders::parse(&file[..0x3C]).unwrap_err(),
+            PeError::Truncated
+        );
+        assert_eq!(
+            PeHeaders::parse(&file[..NT + 2]).unwrap_err(),
+            PeError::Truncated
+        );
+        assert_eq!(
+            PeHeaders::parse(&file[..OPTIONAL + 1]).unwrap_err(),
+            PeError::Truncated
+        );
+        assert_eq!(
+            PeHeaders::parse(&file[..OPTIONAL + 0x60]).unwrap_err(),
+            PeError::Truncated
+        );
+        // The second section table entry is cut short
+        assert_eq!(
+            PeHeaders::parse(&file[..SECTION_TABLE + 40 + 20]).unwrap_err(),
+            PeError::Truncated
+        );
+        assert!(PeHeaders::parse(&file[..SECTION_TABLE + 80]).is_ok());
+    }
+
+    #[test]
+    fn rejects_e_lfanew_past_the_end() {
+        let mut file = two_sections();
+        fixture::put_u32(&mut file, 0x3C, u32::MAX);
+
+        assert_eq!(PeHeaders::parse(&file).unwrap_err(), PeError::Truncated);
+    }
+
+    #[test]
+    fn rejects_bad_signatures() {
+        let mut file = two_sections();
+        file[1] = b'Y';
+        assert_eq!(
+            PeHeaders::parse(&file).unwrap_err(),
+            PeError::BadDosSignature
+        );
+
+        let mut file = two_sections();
+        file[NT + 1] = b'F';
+        assert_eq!(
+            PeHeaders::parse(&file).unwrap_err(),
+            PeError::BadNtSignature
+        );
+
+        let mut file = two_sections();
+        fixture::put_u32(&mut file, 0x3C, 0x40);
+        assert_eq!(
+            PeHeaders::parse(&file).unwrap_err(),
+            PeError::BadNtSignature
+        );
+    }
+
+    #[test]
+    fn rejects_pe32_plus() {
+        let mut file = two_sections();
+        fixture::put_u16(&mut file, OPTIONAL, 0x20B);
+
+        assert_eq!(
+            PeHeaders::parse(&file).unwrap_err(),
+            PeError::UnsupportedMagic(0x20B)
+        );
+        assert!(Module::from_file(&file).is_err());
+    }
+
+    #[test]
+    fn file_offset_is_limited_to_raw_data() {
+        let mut file = two_sections();
+        // .data keeps 0x200 bytes on disk but maps 0x800
+        fixture::put_u32(&mut file, SECTION_TABLE + 40 + 8, 0x800);
+        let headers = PeHeaders::parse(&file).unwrap();
+
+        assert_eq!(headers.file_offset(0x1000), Some(0x400));
+        assert_eq!(headers.file_offset(0x12FF), Some(0x6FF));
+        // Past the text section's virtual size, within its raw size
+        assert_eq!(headers.file_offset(0x1300), None);
+        assert_eq!(headers.file_offset(0x21FF), Some(0x9FF));
+        assert_eq!(headers.file_offset(0x2200), None);
+        assert_eq!(headers.file_offset(0x500), None);
+    }
+
+    #[test]
+    fn maps_sections_at_their_rva() {
+        let module = Module::from_file(&two_sections()).unwrap();
+
+        assert_eq!(module.base, IMAGE_BASE);
+        let text = &module.headers.sections[0];
+        assert_eq!(module.section_data(text), &[0xCC; 0x300][..]);
+        assert_eq!(module.read(IMAGE_BASE + 0x2000, 4), Some(&[0x11; 4][..]));
+        // Reads may not run past the end of a section
+        assert_eq!(module.read(IMAGE_BASE + 0x12FE, 4), None);
+        assert_eq!(module.read(IMAGE_BASE + 0x12FC, 4), Some(&[0xCC; 4][..]));
+        assert_eq!(
+            module.section_at(IMAGE_BASE + 0x2010).map(Section::name),
+            Some(".data")
+        );
+        assert!(module.section_at(IMAGE_BASE + 0x1300).is_none());
+        assert!(module.section_at(0x1000).is_none());
+    }
+
+    #[test]
+    fn section_data_is_clamped_to_size_of_image() {
+        let mut file = two_sections();
+        // .data claims 0x5000 bytes in a 0x3000-byte image
+        fixture::put_u32(&mut file, SECTION_TABLE + 40 + 8, 0x5000);
+        let module = Module::from_file(&file).unwrap();
+
+        let data = &module.headers.sections[1];
+        assert_eq!(module.section_data(data).len(), 0x1000);
+        assert_eq!(module.read(IMAGE_BASE + 0x2FFC, 4), Some(&[0; 4][..]));
+        assert_eq!(module.read(IMAGE_BASE + 0x2FFE, 4), None);
+
+        // A section that starts past the end of the image maps nothing
+        let outside = Section {
+            virtual_address: 0x4000,
+            ..data.clone()
+        };
+        assert!(module.section_data(&outside).is_empty());
+    }
+
+    #[test]
+    fn from_file_rejects_raw_data_past_the_end() {
+        let mut file = two_sections();
+        file.truncate(0x840);
+
+        assert_eq!(Module::from_file(&file).err(), Some(PeError::Truncated));
+    }
+
+    /// An import directory with two DLLs: KERNEL32.dll with a name table
+    /// (`Sleep` and ordinal 16) and user32.dll without one (`MessageBoxA`)
+    fn with_imports() -> Module {
+        let mut idata = vec![0u8; 0xC0];
+        // Descriptors: name table, timestamp, forwarder chain, dll name, address table
+        for (i, value) in [0x2040, 0, 0, 0x2080, 0x2060, 0, 0, 0, 0x2090, 0x2070]
+            .into_iter()
+            .enumerate()
+        {
+            fixture::put_u32(&mut idata, i * 4, value);
+        }
+        for (offset, values) in [
+            (0x40, [0x20A0, 0x8000_0010]),
+            (0x60, [0x20A0, 0x8000_0010]),
+            (0x70, [0x20B0, 0]),
+        ] {
+            fixture::put_u32(&mut idata, offset, values[0]);
+            fixture::put_u32(&mut idata, offset + 4, values[1]);
+        }
+        for (offset, text) in [
+            (0x80, &b"KERNEL32.dll"[..]),
+            (0x90, b"user32.dll"),
+            (0xA2, b"Sleep"),
+            (0xB2, b"MessageBoxA"),
+        ] {
+            idata[offset..offset + text.len()].copy_from_slice(text);
+        }
+
+        fixture::module(
+            &[
+                (".text", 0x1000, vec![0xCC; 0x10], CODE),
+                (".idata", 0x2000, idata, DATA),
+            ],
+            &[(IMAGE_DIRECTORY_ENTRY_IMPORT, 0x2000, 0x3C)],
+        )
+    }
+
+    #[test]
+    fn reads_imports_by_name_and_ordinal() {
+        let import = |dll: &str, function, slot| Import {
+            dll: dll.to_string(),
+            function,
+            slot: IMAGE_BASE + slot,
+        };
+
+        assert_eq!(
+            with_imports().imports(),
+            vec![
+                import("KERNEL32.dll", ImportName::Name("Sleep".into()), 0x2060),
+                import("KERNEL32.dll", ImportName::Ordinal(16), 0x2064),
+                import("user32.dll", ImportName::Name("MessageBoxA".into()), 0x2070),
+            ]
+        );
+    }
+
+    #[test]
+    fn imports_are_read_from_the_name_table_after_binding() {
+        let mut module = with_imports();
+        // The loader overwrites the IAT with function addresses
+        let image = module.mapped.as_mut().unwrap();
+        image[0x2060..0x2064].copy_from_slice(&0x7700_1000u32.to_le_bytes());
+
+        assert_eq!(
+            module.imports()[0].function,
+            ImportName::Name("Sleep".into())
+        );
+    }
+
+    #[test]
+    fn import_slot_ignores_dll_case() {
+        let module = with_imports();
+
+        assert_eq!(
+            module.import_slot("kernel32.DLL", "Sleep"),
+            Some(IMAGE_BASE + 0x2060)
+        );
+        assert_eq!(
+            module.import_slot("USER32.dll", "MessageBoxA"),
+            Some(IMAGE_BASE + 0x2070)
+        );
+        assert_eq!(module.import_slot("kernel32.dll", "sleep"), None);
+        assert_eq!(module.import_slot("user32.dll", "Sleep"), None);
+        assert!(
+            fixture::module(&[(".text", 0x1000, vec![0; 0x10], CODE)], &[])
+                .imports()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn strings_must_end_within_their_section() {
+        let mut file = two_sections();
+        // .data is 0x80 bytes of 0x11 with a NUL at 0x10
+        file[0x800 + 0x10] = 0;
+        let module = Module::from_file(&file).unwrap();
+
+        assert_eq!(
+            module.read_str(IMAGE_BASE + 0x200C),
+            Some("\x11\x11\x11\x11")
+        );
+        assert_eq!(module.read_str(IMAGE_BASE + 0x2011), None);
+        assert_eq!(module.read_str(IMAGE_BASE + 0x2080), None);
+
+        // Inside the section's claimed size but past SizeOfImage
+        fixture::put_u32(&mut file, SECTION_TABLE + 40 + 8, 0x5000);
+        let module = Module::from_file(&file).unwrap();
+        assert_eq!(module.read_str(IMAGE_BASE + 0x4000), None);
+    }
+}
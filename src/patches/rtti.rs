@@ -0,0 +1,311 @@
+//! MSVC RTTI based vtable discovery
+//!
+//! Dunia is built with RTTI enabled, so every polymorphic class has a
+//! TypeDescriptor holding its mangled name (".?AVCConsoleService@@"). From there
+//! the 32-bit MSVC layout lets us walk to the vtables:
+//!
+//! ```text
+//! TypeDescriptor            { pVFTable, spare, name[] }
+//! CompleteObjectLocator     { signature = 0, offset, cdOffset, pTypeDescriptor, pClassDescriptor }
+//! vtable[-1]                = &CompleteObjectLocator
+//! vtable[0..]               = virtual functions
+//! ```
+//!
+//! A class with multiple inheritance has one locator and vtable per base
+//! subobject, distinguished by `offset` (0 for the primary vtable).
+
+use crate::patches::operand::read_u32;
+use crate::patches::pattern::{Pattern, hex};
+use crate::patches::pe::Module;
+use crate::patches::sigscan::{Scope, scan_module_all};
+use crate::patches::xref::{XrefError, find_string};
+
+/// Reasons a class's vtable could not be found
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RttiError {
+    /// No TypeDescriptor with this name
+    TypeNotFound,
+    /// The mangled name occurs more than once, so the TypeDescriptor is unknown
+    TypeAmbiguous(usize),
+    /// The TypeDescriptor exists but no CompleteObjectLocator refers to it
+    NoLocator,
+    /// Locators exist but no vtable refers to any of them
+    NoVTable,
+    /// The requested slot is past the end of the vtable
+    SlotOutOfRange { index: usize, count: usize },
+}
+
+impl std::fmt::Display for RttiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RttiError::TypeNotFound => write!(f, "type descriptor not found"),
+            RttiError::TypeAmbiguous(count) => {
+                write!(f, "type name is ambiguous ({} copies)", count)
+            }
+            RttiError::NoLocator => write!(f, "no complete object locator"),
+            RttiError::NoVTable => write!(f, "no vtable"),
+            RttiError::SlotOutOfRange { index, count } => {
+                write!(f, "slot {} out of range ({} slots)", index, count)
+            }
+        }
+    }
+}
+
+/// A vtable belonging to a class
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VTable {
+    /// Address of the first slot
+    pub address: usize,
+    /// Offset of the subobject this vtable belongs to (0 for the primary vtable)
+    pub offset: u32,
+}
+
+/// Address of the TypeDescriptor for `class`
+///
+/// `class` is either a plain name ("CConsoleService"), tried as both a class
+/// (`.?AV`) and a struct (`.?AU`), or an already mangled name (".?AV...@@").
+pub fn type_descriptor(module: &Module, class: &str) -> Result<usize, RttiError> {
+    let names = if class.starts_with(".?A") {
+        vec![class.to_string()]
+    } else {
+        vec![format!(".?AV{}@@", class), format!(".?AU{}@@", class)]
+    };
+
+    for name in &names {
+        match find_string(module, name) {
+            // The name starts 8 bytes in, after pVFTable and spare
+            Ok(address) => return Ok(address - 8),
+            Err(XrefError::StringAmbiguous(count)) => return Err(RttiError::TypeAmbiguous(count)),
+            Err(_) => {}
+        }
+    }
+
+    Err(RttiError::TypeNotFound)
+}
+
+/// Every vtable of `class`, ordered by subobject offset
+pub fn vtables(module: &Module, class: &str) -> Result<Vec<VTable>, RttiError> {
+    let descriptor = type_descriptor(module, class)?;
+
+    // signature = 0 | offset | cdOffset | pTypeDescriptor
+    let locator = Pattern::parse(&format!(
+        "00 00 00 00 ?? ?? ?? ?? ?? ?? ?? ?? {}",
+        hex(&(descriptor as u32).to_le_bytes())
+    ))
+    .map_err(|_| RttiError::NoLocator)?;
+
+    let locators = scan_module_all(module, &locator, Scope::Data);
+    if locators.is_empty() {
+        return Err(RttiError::NoLocator);
+    }
+
+    let mut tables = Vec::new();
+    for col in locators {
+        let Ok(offset) = read_u32(module, col + 4) else {
+            continue;
+        };
+
+        let reference =
+            Pattern::parse(&hex(&(col as u32).to_le_bytes())).map_err(|_| RttiError::NoVTable)?;
+
+        for slot in scan_module_all(module, &reference, Scope::Data) {
+            let address = slot + 4;
+            if !slots(module, address).is_empty() {
+                tables.push(VTable { address, offset });
+            }
+        }
+    }
+
+    if tables.is_empty() {
+        return Err(RttiError::NoVTable);
+    }
+
+    tables.sort_by_key(|table| (table.offset, table.address));
+    Ok(tables)
+}
+
+/// The primary vtable of `class` (subobject offset 0)
+pub fn vtable(module: &Module, class: &str) -> Result<usize, RttiError> {
+    vtables(module, class)?
+        .into_iter()
+        .find(|table| table.offset == 0)
+        .map(|table| table.address)
+        .ok_or(RttiError::NoVTable)
+}
+
+/// Function pointers stored in the vtable at `address`
+///
+/// The table ends at the first entry that does not point into an executable
+/// section, which is normally the locator pointer of the next vtable.
+pub fn slots(module: &Module, address: usize) -> Vec<usize> {
+    let mut functions = Vec::new();
+
+    while let Ok(function) = read_u32(module, address + functions.len() * 4) {
+        let function = function as usize;
+        match module.section_at(function) {
+            Some(section) if section.is_executable() => functions.push(function),
+            _ => break,
+        }
+    }
+
+    functions
+}
+
+/// Function pointer in slot `index` of the primary vtable of `class`
+#[allow(dead_code)]
+pub fn vtable_slot(module: &Module, class: &str, index: usize) -> Result<usize, RttiError> {
+    let functions = slots(module, vtable(module, class)?);
+    functions
+        .get(index)
+        .copied()
+        .ok_or(RttiError::SlotOutOfRange {
+            index,
+            count: functions.len(),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::patches::fixture::{self, CODE, IMAGE_BASE, RDATA};
+
+    const TEXT: usize = IMAGE_BASE + 0x1000;
+    const RTTI: usize = IMAGE_BASE + 0x2000;
+
+    /// TypeDescriptors, locators and vtables laid out the way MSVC emits them
+    ///
+    /// - `CFoo` (class) has a primary vtable at +0x184 and a second one for
+    ///   the subobject at offset 8 at +0x194
+    /// - `CBar` (struct) has one vtable at +0x1A0
+    /// - `CDup` has two TypeDescriptors with the same name
+    /// - `CNoVt` has a locator but no vtable, `CNoCol` not even a locator
+    fn module() -> Module {
+        let mut rtti = vec![0u8; 0x200];
+        let mut put = |offset: usize, value: usize| {
+            rtti[offset..offset + 4].copy_from_slice(&(value as u32).to_le_bytes());
+        };
+
+        // CompleteObjectLocator { signature, offset, cdOffset, pTypeDescriptor, pClassDescriptor }
+        put(0x08C, RTTI);
+        put(0x0A4, 8);
+        put(0x0AC, RTTI);
+        put(0x0CC, RTTI + 0x20);
+        put(0x0EC, RTTI + 0x100);
+
+        // vtable[-1] is the locator, followed by the function pointers
+        for (offset, value) in [
+            (0x180, RTTI + 0x80),
+            (0x184, TEXT),
+            (0x188, TEXT + 0x10),
+            (0x18C, TEXT + 0x20),
+            (0x190, RTTI + 0xA0),
+            (0x194, TEXT + 0x30),
+            (0x19C, RTTI + 0xC0),
+            (0x1A0, TEXT + 0x40),
+            (0x1A4, TEXT + 0x50),
+        ] {
+            put(offset, value);
+        }
+
+        // TypeDescriptor { pVFTable, spare, name }
+        for (offset, name) in [
+            (0x000, ".?AVCFoo@@"),
+            (0x020, ".?AUCBar@@"),
+            (0x040, ".?AVCDup@@"),
+            (0x060, ".?AVCDup@@"),
+            (0x100, ".?AVCNoVt@@"),
+            (0x140, ".?AVCNoCol@@"),
+        ] {
+            rtti[offset + 8..offset + 8 + name.len()].copy_from_slice(name.as_bytes());
+        }
+
+        fixture::module(
+            &[
+                (".text", 0x1000, vec![0xC3; 0x100], CODE),
+                (".rdata", 0x2000, rtti, RDATA),
+            ],
+            &[],
+        )
+    }
+
+    #[test]
+    fn finds_type_descriptors() {
+        let module = module();
+
+        assert_eq!(type_descriptor(&module, "CFoo"), Ok(RTTI));
+        assert_eq!(type_descriptor(&module, ".?AVCFoo@@"), Ok(RTTI));
+        assert_eq!(type_descriptor(&module, "CBar"), Ok(RTTI + 0x20));
+        assert_eq!(
+            type_descriptor(&module, ".?AVCBar@@"),
+            Err(RttiError::TypeNotFound)
+        );
+        assert_eq!(
+            type_descriptor(&module, "CFo"),
+            Err(RttiError::TypeNotFound)
+        );
+    }
+
+    #[test]
+    fn ambiguous_type_names_are_reported() {
+        let module = module();
+
+        assert_eq!(
+            type_descriptor(&module, "CDup"),
+            Err(RttiError::TypeAmbiguous(2))
+        );
+        assert_eq!(vtable(&module, "CDup"), Err(RttiError::TypeAmbiguous(2)));
+    }
+
+    #[test]
+    fn finds_every_vtable_of_a_class() {
+        let module = module();
+
+        assert_eq!(
+            vtables(&module, "CFoo"),
+            Ok(vec![
+                VTable {
+                    address: RTTI + 0x184,
+                    offset: 0
+                },
+                VTable {
+                    address: RTTI + 0x194,
+                    offset: 8
+                },
+            ])
+        );
+        assert_eq!(vtable(&module, "CFoo"), Ok(RTTI + 0x184));
+        assert_eq!(vtable(&module, "CBar"), Ok(RTTI + 0x1A0));
+    }
+
+    #[test]
+    fn slots_end_at_the_next_locator() {
+        let module = module();
+
+        assert_eq!(
+            slots(&module, RTTI + 0x184),
+            [TEXT, TEXT + 0x10, TEXT + 0x20]
+        );
+        assert_eq!(slots(&module, RTTI + 0x194), [TEXT + 0x30]);
+        assert_eq!(slots(&module, RTTI + 0x1A0), [TEXT + 0x40, TEXT + 0x50]);
+        assert!(slots(&module, RTTI + 0x1FC).is_empty());
+    }
+
+    #[test]
+    fn vtable_slots() {
+        let module = module();
+
+        assert_eq!(vtable_slot(&module, "CFoo", 1), Ok(TEXT + 0x10));
+        assert_eq!(
+            vtable_slot(&module, "CFoo", 3),
+            Err(RttiError::SlotOutOfRange { index: 3, count: 3 })
+        );
+    }
+
+    #[test]
+    fn missing_locators_and_vtables() {
+        let module = module();
+
+        assert_eq!(vtables(&module, "CNoVt"), Err(RttiError::NoVTable));
+        assert_eq!(vtables(&module, "CNoCol"), Err(RttiError::NoLocator));
+    }
+}
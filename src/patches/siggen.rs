@@ -0,0 +1,381 @@
+//! Signature generation for new patch sites
+//!
+//! Given an address in a module, finds the shortest pattern that matches only
+//! there. Bytes that are likely to change between builds are wildcarded:
+//!
+//! - absolute addresses listed in the base relocation table
+//! - rel32 displacements of `call` (E8), `jmp` (E9) and `jcc` (0F 80-8F) whose
+//!   target lies in an executable section
+//!
+//! The pattern may start a little before the address, in which case the
+//! address is marked as its patch point (`|`).
+
+use crate::patches::pattern::Pattern;
+use crate::patches::pe::Module;
+use crate::patches::sigscan::{MultiScanner, Scope};
+
+/// How far before the target a signature may start
+const MAX_LEAD: usize = 32;
+/// Longest signature we are willing to produce
+const MAX_LEN: usize = 128;
+/// Wildcard runs at least this long are written as `??{N}`
+const MIN_REPEAT: usize = 8;
+
+/// Reasons no signature could be generated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SigGenError {
+    /// The address is not inside a code or data section of the module
+    OutsideModule(usize),
+    /// No pattern of up to [`MAX_LEN`] bytes matches only this address
+    NotUnique,
+}
+
+impl std::fmt::Display for SigGenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SigGenError::OutsideModule(address) => {
+                write!(f, "0x{:08X} is not in a code or data section", address)
+            }
+            SigGenError::NotUnique => {
+                write!(f, "no unique signature within {} bytes", MAX_LEN)
+            }
+        }
+    }
+}
+
+/// A generated signature
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Generated {
+    /// The pattern in [`Pattern`] syntax
+    pub pattern: String,
+    /// Which sections it must be searched in
+    pub scope: Scope,
+}
+
+impl Generated {
+    /// The signature as a Rust expression, ready to paste into `patches::signatures`
+    pub fn definition(&self, name: &str) -> String {
+        let constructor = match self.scope {
+            Scope::Code => "code",
+            Scope::Data => "data",
+        };
+        format!(
+            "Signature::{}(\"{}\", \"{}\")",
+            constructor, name, self.pattern
+        )
+    }
+}
+
+/// Generate the shortest unique signature for `target`
+pub fn generate(module: &Module, target: usize) -> Result<Generated, SigGenError> {
+    let section = module
+        .section_at(target)
+        .ok_or(SigGenError::OutsideModule(target))?;
+    let scope = [Scope::Code, Scope::Data]
+        .into_iter()
+        .find(|scope| scope.includes(section))
+        .ok_or(SigGenError::OutsideModule(target))?;
+
+    // The window of bytes a signature can be built from, clamped to the section
+    let section_start = module.base + section.virtual_address as usize;
+    let section_end = section_start + module.section_data(section).len();
+    let low = target.saturating_sub(MAX_LEAD).max(section_start);
+    let high = (target + MAX_LEN).min(section_end);
+    let data = module
+        .read(low, high - low)
+        .ok_or(SigGenError::OutsideModule(target))?;
+    let wildcard = wildcards(module, scope, low, data);
+
+    // Try every start position at once: one pattern per start, covering the target
+    let mut prefixes = Vec::new();
+    for start in (0..=target - low).filter(|&start| !wildcard[start]) {
+        let mut len = target - low - start + 1;
+        while start + len < data.len() && fixed(&wildcard[start..start + len]) < 2 {
+            len += 1;
+        }
+
+        let mask: String = wildcard[start..start + len]
+            .iter()
+            .map(|&w| if w { '?' } else { 'x' })
+            .collect();
+        if let Ok(pattern) = Pattern::from_code(&data[start..start + len], &mask) {
+            prefixes.push((start, pattern));
+        }
+    }
+
+    let mut scanner = MultiScanner::new();
+    for (_, pattern) in &prefixes {
+        scanner.add(pattern, scope);
+    }
+    let matches = scanner.scan_module(module);
+
+    // Extend each prefix byte by byte until it only matches at its own start
+    let mut best: Option<(usize, usize)> = None;
+    for ((start, prefix), mut candidates) in prefixes.iter().zip(matches) {
+        let own = low + start;
+        let mut len = prefix.len();
+
+        while candidates.len() > 1 && start + len < data.len() {
+            if !wildcard[start + len] {
+                let byte = data[start + len];
+                candidates.retain(|&candidate| {
+                    module
+                        .read(candidate + len, 1)
+                        .is_some_and(|read| read[0] == byte)
+                });
+            }
+            len += 1;
+        }
+
+        let shorter = best.is_none_or(|(_, best_len)| len <= best_len);
+        if candidates == [own] && shorter {
+            best = Some((*start, len));
+        }
+    }
+
+    let (start, len) = best.ok_or(SigGenError::NotUnique)?;
+    Ok(Generated {
+        pattern: to_pattern(
+            &data[start..start + len],
+            &wildcard[start..start + len],
+            target - low - start,
+        ),
+        scope,
+    })
+}
+
+/// Which bytes of `data` (located at `address`) should be wildcarded
+fn wildcards(module: &Module, scope: Scope, address: usize, data: &[u8]) -> Vec<bool> {
+    let mut wildcard = vec![false; data.len()];
+    let mut mark = |offset: usize, len: usize| {
+        let end = (offset + len).min(wildcard.len());
+        wildcard[offset.min(end)..end].fill(true);
+    };
+
+    for fixup in module.relocations() {
+        if fixup + 4 > address && fixup < address + data.len() {
+            // A fixup may straddle the start of the window
+            let offset = fixup.saturating_sub(address);
+            let len = 4 - address.saturating_sub(fixup);
+            mark(offset, len);
+        }
+    }
+
+    if scope == Scope::Code {
+        for i in 0..data.len() {
+            let operand = match (data[i], data.get(i + 1)) {
+                (0xE8 | 0xE9, _) => i + 1,
+                (0x0F, Some(0x80..=0x8F)) => i + 2,
+                _ => continue,
+            };
+
+            let Some(rel) = data.get(operand..operand + 4) else {
+                continue;
+            };
+            let displacement = i32::from_le_bytes([rel[0], rel[1], rel[2], rel[3]]) as isize;
+            let destination = (address + operand + 4).wrapping_add_signed(displacement);
+
+            if module
+                .section_at(destination)
+                .is_some_and(|section| section.is_executable())
+            {
+                mark(operand, 4);
+            }
+        }
+    }
+
+    wildcard
+}
+
+/// Number of fixed bytes in a mask
+fn fixed(wildcard: &[bool]) -> usize {
+    wildcard.iter().filter(|&&w| !w).count()
+}
+
+/// Write bytes and wildcards in pattern syntax, with `|` before `patch_point` if nonzero
+fn to_pattern(data: &[u8], wildcard: &[bool], patch_point: usize) -> String {
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < data.len() {
+        if i == patch_point && patch_point != 0 {
+            tokens.push("|".to_string());
+        }
+
+        if !wildcard[i] {
+            tokens.push(format!("{:02X}", data[i]));
+            i += 1;
+            continue;
+        }
+
+        // Group a run of wildcards, without crossing the patch point
+        let mut run = 1;
+        while i + run < data.len() && wildcard[i + run] && i + run != patch_point {
+            run += 1;
+        }
+
+        if run >= MIN_REPEAT {
+            tokens.push(format!("??{{{}}}", run));
+        } else {
+            tokens.extend(std::iter::repeat_n("??".to_string(), run));
+        }
+        i += run;
+    }
+
+    tokens.join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::patches::fixture::{self, CODE, IMAGE_BASE, RDATA};
+    use crate::patches::pe::IMAGE_DIRECTORY_ENTRY_BASERELOC;
+    use crate::patches::sigscan::scan_module_all;
+
+    const TEXT: usize = IMAGE_BASE + 0x1000;
+    const RDATA_START: usize = IMAGE_BASE + 0x2000;
+
+    fn rel32(next: usize, target: usize) -> [u8; 4] {
+        (target.wrapping_sub(next) as u32).to_le_bytes()
+    }
+
+    /// .text padded with int3 and `code` placed at the given offsets, a string
+    /// in .rdata, and a relocation for the `push imm32` at .text+0x20
+    fn module(code: &[(usize, &[u8])]) -> Module {
+        let mut text = vec![0xCC; 0x100];
+        for (offset, bytes) in code {
+            text[*offset..offset + bytes.len()].copy_from_slice(bytes);
+        }
+
+        let mut rdata = vec![0u8; 0x20];
+        rdata[..10].copy_from_slice(b"archBlink\0");
+
+        // { page rva, block size, HIGHLOW entry, padding }
+        let mut reloc = vec![0u8; 0x0C];
+        reloc[..4].copy_from_slice(&0x1000u32.to_le_bytes());
+        reloc[4..8].copy_from_slice(&0x0Cu32.to_le_bytes());
+        reloc[8..10].copy_from_slice(&0x3021u16.to_le_bytes());
+
+        fixture::module(
+            &[
+                (".text", 0x1000, text, CODE),
+                (".rdata", 0x2000, rdata, RDATA),
+                (".reloc", 0x3000, reloc, RDATA),
+            ],
+            &[(IMAGE_DIRECTORY_ENTRY_BASERELOC, 0x3000, 0x0C)],
+        )
+    }
+
+    /// push imm32 (relocated), call, jmp and jz into .text, and a call into .rdata
+    fn branches() -> Vec<u8> {
+        let mut code = vec![0x68];
+        code.extend_from_slice(&(TEXT as u32 + 0x80).to_le_bytes());
+        code.push(0xE8);
+        code.extend_from_slice(&rel32(TEXT + 0x2A, TEXT + 0x80));
+        code.push(0xE9);
+        code.extend_from_slice(&rel32(TEXT + 0x2F, TEXT + 0x90));
+        code.extend_from_slice(&[0x0F, 0x84]);
+        code.extend_from_slice(&rel32(TEXT + 0x35, TEXT + 0xA0));
+        code.push(0xE8);
+        code.extend_from_slice(&rel32(TEXT + 0x3A, RDATA_START));
+        code.push(0xC3);
+        code
+    }
+
+    #[test]
+    fn wildcards_relocations_and_branch_displacements() {
+        let module = module(&[(0x20, &branches())]);
+        let data = module.read(TEXT + 0x20, 0x1B).unwrap();
+
+        let wildcard = wildcards(&module, Scope::Code, TEXT + 0x20, data);
+        let positions: Vec<_> = (0..data.len()).filter(|&i| wildcard[i]).collect();
+        let expected: Vec<_> = (1..5).chain(6..10).chain(11..15).chain(17..21).collect();
+        assert_eq!(positions, expected, "the call into .rdata is kept");
+
+        // A relocation that starts before the window
+        let data = module.read(TEXT + 0x23, 4).unwrap();
+        assert_eq!(
+            wildcards(&module, Scope::Code, TEXT + 0x23, data),
+            [true, true, false, false]
+        );
+    }
+
+    #[test]
+    fn generates_the_shortest_unique_pattern() {
+        let module = module(&[(0x20, &branches())]);
+
+        // The padding before the push is shorter than the push's wildcards
+        let generated = generate(&module, TEXT + 0x20).unwrap();
+        assert_eq!(generated.pattern, "CC | 68");
+        assert_eq!(
+            generated.definition("Push"),
+            "Signature::code(\"Push\", \"CC | 68\")"
+        );
+        assert_eq!(generate(&module, TEXT + 0x2F).unwrap().pattern, "0F 84");
+
+        let generated = generate(&module, RDATA_START + 4).unwrap();
+        assert_eq!(generated.scope, Scope::Data);
+        assert_eq!(generated.pattern, "42 6C");
+    }
+
+    #[test]
+    fn starts_before_the_target_when_that_is_shorter() {
+        // The same function body twice, only the first byte differs
+        let body = [0x8B, 0xEC, 0x33, 0xC0, 0xC3];
+        let module = module(&[
+            (0x10, &[0x55]),
+            (0x11, &body),
+            (0x40, &[0x56]),
+            (0x41, &body),
+        ]);
+
+        let generated = generate(&module, TEXT + 0x11).unwrap();
+        assert_eq!(generated.pattern, "55 | 8B");
+
+        let pattern = Pattern::parse(&generated.pattern).unwrap();
+        assert_eq!(
+            scan_module_all(&module, &pattern, Scope::Code),
+            [TEXT + 0x10]
+        );
+        assert_eq!(pattern.patch_point(), 1);
+    }
+
+    #[test]
+    fn writes_wildcard_runs_and_the_patch_point() {
+        let data = [0xE8, 0, 0, 0, 0, 0x68, 1, 2, 3, 4, 5, 6, 7, 8, 9, 0x90];
+        let mut wildcard = [false; 16];
+        wildcard[1..5].fill(true);
+        wildcard[6..15].fill(true);
+
+        assert_eq!(
+            to_pattern(&data, &wildcard, 0),
+            "E8 ?? ?? ?? ?? 68 ??{9} 90"
+        );
+        // A run is split at the patch point
+        assert_eq!(
+            to_pattern(&data, &wildcard, 3),
+            "E8 ?? ?? | ?? ?? 68 ??{9} 90"
+        );
+        assert_eq!(
+            to_pattern(&data, &wildcard, 8),
+            "E8 ?? ?? ?? ?? 68 ?? ?? | ?? ?? ?? ?? ?? ?? ?? 90"
+        );
+    }
+
+    #[test]
+    fn reports_addresses_without_a_unique_signature() {
+        let module = module(&[]);
+
+        // int3 padding looks the same everywhere
+        assert_eq!(generate(&module, TEXT + 0x80), Err(SigGenError::NotUnique));
+        assert_eq!(
+            generate(&module, IMAGE_BASE + 0x10),
+            Err(SigGenError::OutsideModule(IMAGE_BASE + 0x10))
+        );
+        // .reloc is neither code nor .rdata/.data
+        assert_eq!(
+            generate(&module, IMAGE_BASE + 0x3000),
+            Err(SigGenError::OutsideModule(IMAGE_BASE + 0x3000))
+        );
+    }
+}
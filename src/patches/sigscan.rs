@@ -1,136 +1,551 @@
 //! Signature scanning for pattern matching in memory
 //!
-//! Supports wildcard bytes using `??` in patterns.
-
-use windows::Win32::System::Memory::{
-    MEMORY_BASIC_INFORMATION, PAGE_EXECUTE_READ, PAGE_EXECUTE_READWRITE, PAGE_READONLY,
-    PAGE_READWRITE, VirtualQuery,
-};
-use windows::Win32::System::SystemInformation::{GetSystemInfo, SYSTEM_INFO};
-
-/// A parsed signature pattern with optional wildcard bytes
-pub struct Pattern {
-    bytes: Vec<u8>,
-    mask: Vec<bool>, // true = must match, false = wildcard
-}
-
-impl Pattern {
-    /// Parse a pattern string like "80 79 ?? ?? 8B 54"
-    pub fn parse(pattern: &str) -> Option<Self> {
-        let mut bytes = Vec::new();
-        let mut mask = Vec::new();
-
-        for part in pattern.split_whitespace() {
-            if part == "??" || part == "?" {
-                bytes.push(0);
-                mask.push(false);
-            } else {
-                let byte = u8::from_str_radix(part, 16).ok()?;
-                bytes.push(byte);
-                mask.push(true);
-            }
+//! See [`crate::patches::pattern`] for the pattern syntax. Scans are scoped to the
+//! sections described by the module's PE headers, so code signatures are only
+//! searched in executable sections and string signatures only in data sections.
+
+use crate::patches::pattern::{ParseError, Pattern};
+use crate::patches::pe::{Module, Section};
+
+/// Which sections of a module a signature is searched in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    /// Executable sections (`.text`)
+    Code,
+    /// Initialized data sections (`.rdata`, `.data`)
+    Data,
+}
+
+impl Scope {
+    /// Check whether a section belongs to this scope
+    pub fn includes(self, section: &Section) -> bool {
+        if !section.is_readable() {
+            return false;
         }
 
-        if bytes.is_empty() {
-            return None;
+        match self {
+            Scope::Code => section.is_executable(),
+            Scope::Data => !section.is_executable() && matches!(section.name(), ".rdata" | ".data"),
         }
+    }
+}
+
+/// Scan the sections of a module that fall within `scope` for every match of a pattern
+///
+/// Returns the addresses of all matches, in ascending order.
+pub fn scan_module_all(module: &Module, pattern: &Pattern, scope: Scope) -> Vec<usize> {
+    let mut scanner = MultiScanner::new();
+    scanner.add(pattern, scope);
+    scanner.scan_module(module).swap_remove(0)
+}
+
+/// Searches for many patterns at once, in a single pass over each section
+///
+/// Every pattern is indexed by an anchor byte, its rarest fixed byte. While
+/// walking a section only the patterns whose anchor equals the current byte are
+/// compared in full, so adding patterns costs a few extra comparisons at
+/// candidate positions rather than another pass over the whole module.
+pub struct MultiScanner<'a> {
+    entries: Vec<Entry<'a>>,
+}
+
+/// A registered pattern, its scope and its anchor (offset and byte)
+type Entry<'a> = (&'a Pattern, Scope, Option<(usize, u8)>);
 
-        Some(Self { bytes, mask })
+impl<'a> MultiScanner<'a> {
+    pub fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+        }
+    }
+
+    /// Register a pattern, returning its index into the results of [`Self::scan_module`]
+    pub fn add(&mut self, pattern: &'a Pattern, scope: Scope) -> usize {
+        self.entries.push((pattern, scope, pattern.anchor()));
+        self.entries.len() - 1
+    }
+
+    /// Find every match of every registered pattern within its scope
+    ///
+    /// Returns one list of addresses per registered pattern, in ascending order.
+    pub fn scan_module(&self, module: &Module) -> Vec<Vec<usize>> {
+        let mut results = vec![Vec::new(); self.entries.len()];
+
+        for scope in [Scope::Code, Scope::Data] {
+            let ids: Vec<usize> = (0..self.entries.len())
+                .filter(|&id| self.entries[id].1 == scope)
+                .collect();
+            if ids.is_empty() {
+                continue;
+            }
+
+            let buckets = self.buckets(&ids);
+            for section in module.headers.sections.iter().filter(|s| scope.includes(s)) {
+                let start = module.base + section.virtual_address as usize;
+                self.scan_slice(module.section_data(section), start, &buckets, &mut results);
+            }
+        }
+
+        for matches in &mut results {
+            matches.sort_unstable();
+        }
+        results
     }
 
-    /// Check if pattern matches at the given memory location
+    /// Group the patterns listed in `ids` by anchor byte
     ///
-    /// # Safety
-    /// Caller must ensure `ptr` points to readable memory of at least `self.len()` bytes.
-    #[inline]
-    pub unsafe fn matches_at(&self, ptr: *const u8) -> bool {
-        for (i, (&pattern_byte, &must_match)) in self.bytes.iter().zip(self.mask.iter()).enumerate()
-        {
-            if must_match && unsafe { *ptr.add(i) } != pattern_byte {
-                return false;
+    /// Index 256 holds the patterns without any fixed byte, which are tried everywhere.
+    fn buckets(&self, ids: &[usize]) -> Vec<Vec<usize>> {
+        let mut buckets = vec![Vec::new(); 257];
+        for &id in ids {
+            let (_, _, anchor) = self.entries[id];
+            match anchor {
+                Some((_, byte)) => buckets[byte as usize].push(id),
+                None => buckets[256].push(id),
             }
         }
-        true
+        buckets
     }
 
-    /// Get the length of the pattern
-    pub fn len(&self) -> usize {
-        self.bytes.len()
+    /// Scan `data` (located at address `start`) for the bucketed patterns
+    fn scan_slice(
+        &self,
+        data: &[u8],
+        start: usize,
+        buckets: &[Vec<usize>],
+        results: &mut [Vec<usize>],
+    ) {
+        for (i, &byte) in data.iter().enumerate() {
+            for &id in buckets[byte as usize].iter().chain(&buckets[256]) {
+                let (pattern, _, anchor) = self.entries[id];
+                let offset = anchor.map_or(0, |(offset, _)| offset);
+                let Some(begin) = i.checked_sub(offset) else {
+                    continue;
+                };
+
+                if pattern.matches(&data[begin..]) {
+                    results[id].push(start + begin);
+                }
+            }
+        }
+    }
+}
+
+/// A resolved signature: where it matched and what its captures contain
+#[derive(Debug, Clone)]
+pub struct Match {
+    /// Address of the first byte of the pattern
+    pub address: usize,
+    /// Address of the pattern's `|` marker (the match address if it has none)
+    pub patch_point: usize,
+    captures: Vec<(String, Capture)>,
+}
+
+impl Match {
+    /// Copy out the patch point and captures of `pattern` matched at `address`
+    fn new(module: &Module, pattern: &Pattern, address: usize) -> Option<Self> {
+        let mut captures = Vec::with_capacity(pattern.captures().len());
+        for group in pattern.captures() {
+            let capture_address = address + group.offset;
+            let bytes = module.read(capture_address, group.len)?.to_vec();
+            captures.push((
+                group.name.clone(),
+                Capture {
+                    address: capture_address,
+                    bytes,
+                },
+            ));
+        }
+
+        Some(Self {
+            address,
+            patch_point: address + pattern.patch_point(),
+            captures,
+        })
+    }
+
+    /// Look up a named capture
+    pub fn capture(&self, name: &str) -> Option<&Capture> {
+        self.captures
+            .iter()
+            .find(|(capture_name, _)| capture_name == name)
+            .map(|(_, capture)| capture)
+    }
+}
+
+/// The bytes matched by a named capture
+#[derive(Debug, Clone)]
+pub struct Capture {
+    pub address: usize,
+    pub bytes: Vec<u8>,
+}
+
+impl Capture {
+    /// The captured bytes as a little-endian integer (up to 4 bytes)
+    pub fn value(&self) -> u32 {
+        self.bytes
+            .iter()
+            .take(4)
+            .rev()
+            .fold(0, |value, &byte| (value << 8) | byte as u32)
+    }
+
+    /// Destination of a relative branch whose rel8/rel32 displacement is this capture
+    ///
+    /// The displacement is relative to the end of the capture, which is the end of
+    /// the instruction for `jmp`, `call` and `jcc`.
+    #[allow(dead_code)]
+    pub fn branch_target(&self) -> Option<usize> {
+        let displacement = match self.bytes.len() {
+            1 => self.bytes[0] as i8 as isize,
+            4 => self.value() as i32 as isize,
+            _ => return None,
+        };
+
+        Some((self.address + self.bytes.len()).wrapping_add_signed(displacement))
+    }
+}
+
+/// Reasons a signature could not be resolved to a single address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScanError {
+    /// The pattern string could not be parsed
+    InvalidPattern(ParseError),
+    /// The pattern did not match anywhere in its scope
+    NotFound,
+    /// The pattern matched a different number of times than expected
+    UnexpectedCount { found: usize, expected: usize },
+    /// The original bytes a build table records for the site do not fit it
+    BadOriginal,
+}
+
+impl std::fmt::Display for ScanError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScanError::InvalidPattern(e) => write!(f, "invalid pattern: {}", e),
+            ScanError::NotFound => write!(f, "signature not found"),
+            ScanError::UnexpectedCount { found, expected } => write!(
+                f,
+                "signature is ambiguous ({} matches, expected {})",
+                found, expected
+            ),
+            ScanError::BadOriginal => write!(f, "recorded original bytes do not fit the site"),
+        }
     }
 }
 
-/// Scan a memory region for a pattern
+/// A named pattern together with where to search and how many matches to expect
 ///
-/// # Safety
-/// Caller must ensure `start` to `start + size` is valid readable memory.
-pub unsafe fn scan_region(start: usize, size: usize, pattern: &Pattern) -> Option<usize> {
-    if size < pattern.len() {
-        return None;
+/// By default a signature must match exactly once. Signatures that are known to
+/// match several sites can use [`Signature::nth`] to pick one of them, which still
+/// refuses to resolve if the total number of matches changes.
+#[derive(Debug, Clone, Copy)]
+pub struct Signature {
+    pub name: &'static str,
+    pub pattern: &'static str,
+    pub scope: Scope,
+    /// Number of matches the pattern must have in the module
+    pub expected: usize,
+    /// Which match (in address order) to resolve to
+    pub index: usize,
+}
+
+impl Signature {
+    /// A signature that must match exactly once in executable sections
+    pub const fn code(name: &'static str, pattern: &'static str) -> Self {
+        Self {
+            name,
+            pattern,
+            scope: Scope::Code,
+            expected: 1,
+            index: 0,
+        }
     }
 
-    let end = start + size - pattern.len();
-    let mut addr = start;
+    /// A signature that must match exactly once in data sections
+    pub const fn data(name: &'static str, pattern: &'static str) -> Self {
+        Self {
+            name,
+            pattern,
+            scope: Scope::Data,
+            expected: 1,
+            index: 0,
+        }
+    }
 
-    while addr <= end {
-        if unsafe { pattern.matches_at(addr as *const u8) } {
-            return Some(addr);
+    /// Expect exactly `count` matches and resolve to the `index`th one
+    #[allow(dead_code)]
+    pub const fn nth(self, index: usize, count: usize) -> Self {
+        Self {
+            expected: count,
+            index,
+            ..self
         }
-        addr += 1;
     }
 
-    None
+    /// Find the match this signature refers to, enforcing the expected match count
+    #[allow(dead_code)]
+    pub fn resolve(&self, module: &Module) -> Result<Match, ScanError> {
+        let [result] = resolve_all(module, [self]);
+        result
+    }
+
+    /// Pick the address this signature refers to from all of its matches
+    fn select(&self, matches: &[usize]) -> Result<usize, ScanError> {
+        if matches.is_empty() {
+            return Err(ScanError::NotFound);
+        }
+
+        if matches.len() != self.expected {
+            return Err(ScanError::UnexpectedCount {
+                found: matches.len(),
+                expected: self.expected,
+            });
+        }
+
+        matches.get(self.index).copied().ok_or(ScanError::NotFound)
+    }
 }
 
-/// Scan a module's executable sections for a pattern
+/// Resolve a batch of signatures with a single pass over each section of the module
+pub fn resolve_all<const N: usize>(
+    module: &Module,
+    signatures: [&Signature; N],
+) -> [Result<Match, ScanError>; N] {
+    let mut results = resolve_all_known(module, &signatures, |_| None).into_iter();
+    std::array::from_fn(|_| results.next().unwrap_or(Err(ScanError::NotFound)))
+}
+
+/// Resolve a batch of signatures, trying known addresses before scanning
 ///
-/// Returns the address of the first match, or None if not found.
-pub fn scan_module(module_base: usize, pattern: &Pattern) -> Option<usize> {
-    // Initialize system info (not currently used but may be useful later)
-    let _page_size = unsafe {
-        let mut si = SYSTEM_INFO::default();
-        GetSystemInfo(&mut si);
-        si.dwPageSize as usize
+/// `known` may supply an address for a signature, e.g. from a build's offset
+/// table. It is only used if the pattern matches there; every other signature
+/// is scanned for as in [`resolve_all`], in a single pass. Results are in the
+/// order of `signatures`.
+pub fn resolve_all_known(
+    module: &Module,
+    signatures: &[&Signature],
+    known: impl Fn(&Signature) -> Option<usize>,
+) -> Vec<Result<Match, ScanError>> {
+    let patterns: Vec<_> = signatures
+        .iter()
+        .map(|s| Pattern::parse(s.pattern))
+        .collect();
+
+    let mut verified: Vec<Option<Match>> = patterns
+        .iter()
+        .zip(signatures)
+        .map(|(pattern, signature)| {
+            let pattern = pattern.as_ref().ok()?;
+            let address = known(signature)?;
+            match_at(module, pattern, signature.scope, address)
+        })
+        .collect();
+
+    let mut scanner = MultiScanner::new();
+    let ids: Vec<Result<Option<usize>, ParseError>> = patterns
+        .iter()
+        .zip(signatures)
+        .zip(&verified)
+        .map(|((pattern, signature), verified)| match pattern {
+            Ok(_) if verified.is_some() => Ok(None),
+            Ok(p) => Ok(Some(scanner.add(p, signature.scope))),
+            Err(e) => Err(*e),
+        })
+        .collect();
+
+    let matches = if ids.iter().any(|id| matches!(id, Ok(Some(_)))) {
+        scanner.scan_module(module)
+    } else {
+        Vec::new()
     };
 
-    let mut addr = module_base;
-    let max_scan = 0x10000000; // 256MB max scan range
-
-    while addr < module_base + max_scan {
-        let mut mbi = MEMORY_BASIC_INFORMATION::default();
-        let result = unsafe {
-            VirtualQuery(
-                Some(addr as *const _),
-                &mut mbi,
-                size_of::<MEMORY_BASIC_INFORMATION>(),
-            )
-        };
+    (0..signatures.len())
+        .map(|i| {
+            let id = ids[i].map_err(ScanError::InvalidPattern)?;
+            let Some(id) = id else {
+                return verified[i].take().ok_or(ScanError::NotFound);
+            };
+
+            let address = signatures[i].select(&matches[id])?;
+            let pattern = patterns[i]
+                .as_ref()
+                .map_err(|e| ScanError::InvalidPattern(*e))?;
+            Match::new(module, pattern, address).ok_or(ScanError::NotFound)
+        })
+        .collect()
+}
+
+/// The match of `pattern` at exactly `address`, if it matches there and lies in `scope`
+fn match_at(module: &Module, pattern: &Pattern, scope: Scope, address: usize) -> Option<Match> {
+    if !scope.includes(module.section_at(address)?) {
+        return None;
+    }
+
+    let bytes = module.read(address, pattern.len())?;
+    if !pattern.matches(bytes) {
+        return None;
+    }
+
+    Match::new(module, pattern, address)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate test;
+
+    use super::*;
+    use crate::patches::fixture::{self, CODE, DATA};
+    use crate::patches::pe::Module;
+
+    /// Patterns with anchors, nibble and bit-mask bytes, and none at all
+    const PATTERNS: &[&str] = &[
+        "8B ?? 74",
+        "E8 ?? ?? ?? ??",
+        "74 8B{3}",
+        "C0&C0 00",
+        "00 00",
+        "CC",
+        "?? ??",
+        "8? ?4",
+        "??{5}",
+        "8B 74 CC 00 8B 74 CC 00 E8",
+    ];
+
+    /// xorshift32, so the fixtures are the same on every run
+    fn random(len: usize, mut seed: u32) -> impl Iterator<Item = u8> {
+        (0..len).map(move |_| {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed as u8
+        })
+    }
+
+    /// Random bytes from a small alphabet, so partial and overlapping matches are common
+    fn bytes(len: usize, seed: u32) -> Vec<u8> {
+        const ALPHABET: [u8; 8] = [0x00, 0x74, 0x8B, 0xCC, 0xE8, 0xC3, 0x84, 0x14];
+        random(len, seed)
+            .map(|byte| ALPHABET[byte as usize % ALPHABET.len()])
+            .collect()
+    }
 
-        if result == 0 {
-            break;
+    fn module(code: Vec<u8>, data: Vec<u8>) -> Module {
+        fixture::module(
+            &[
+                (".text", 0x1000, code, CODE),
+                (".data", 0x10000, data, DATA),
+            ],
+            &[],
+        )
+    }
+
+    /// Every match of `pattern` in `scope`, by trying each position in turn
+    fn naive(module: &Module, pattern: &Pattern, scope: Scope) -> Vec<usize> {
+        let mut matches = Vec::new();
+        for section in module.headers.sections.iter().filter(|s| scope.includes(s)) {
+            let data = module.section_data(section);
+            let start = module.base + section.virtual_address as usize;
+            matches.extend(
+                (0..data.len())
+                    .filter(|&i| pattern.matches(&data[i..]))
+                    .map(|i| start + i),
+            );
         }
+        matches
+    }
 
-        // Check if this is a readable code section
-        let protect = mbi.Protect;
-        let is_readable = protect == PAGE_EXECUTE_READ
-            || protect == PAGE_EXECUTE_READWRITE
-            || protect == PAGE_READONLY
-            || protect == PAGE_READWRITE;
+    #[test]
+    fn single_pass_matches_naive_scan() {
+        let mut code = bytes(0x4000, 0x2545_F491);
+        let long = [0x8B, 0x74, 0xCC, 0x00, 0x8B, 0x74, 0xCC, 0x00, 0xE8];
+        code[0x123..0x12C].copy_from_slice(&long);
+        code[0x3FF7..].copy_from_slice(&long);
+        let module = module(code, bytes(0x800, 0x9E37_79B9));
+        let patterns: Vec<Pattern> = PATTERNS
+            .iter()
+            .map(|p| Pattern::parse(p).unwrap())
+            .collect();
 
-        if is_readable && mbi.RegionSize > 0 {
-            let region_start = mbi.BaseAddress as usize;
-            let region_size = mbi.RegionSize;
+        let mut scanner = MultiScanner::new();
+        for pattern in &patterns {
+            scanner.add(pattern, Scope::Code);
+            scanner.add(pattern, Scope::Data);
+        }
+        let results = scanner.scan_module(&module);
 
-            if let Some(found) = unsafe { scan_region(region_start, region_size, pattern) } {
-                return Some(found);
-            }
+        for (i, pattern) in patterns.iter().enumerate() {
+            let code = naive(&module, pattern, Scope::Code);
+            let data = naive(&module, pattern, Scope::Data);
+            assert!(!code.is_empty(), "{} never matches", PATTERNS[i]);
+            assert_eq!(results[2 * i], code, "{} in code", PATTERNS[i]);
+            assert_eq!(results[2 * i + 1], data, "{} in data", PATTERNS[i]);
         }
+    }
 
-        // Move to next region
-        addr = mbi.BaseAddress as usize + mbi.RegionSize;
-        if addr < mbi.BaseAddress as usize {
-            break; // Overflow protection
+    #[test]
+    fn patterns_without_fixed_bytes_match_everywhere() {
+        let module = module(bytes(0x40, 1), bytes(0x10, 2));
+        let pattern = Pattern::parse("?? ?? ??").unwrap();
+        assert_eq!(pattern.anchor(), None);
+
+        let matches = scan_module_all(&module, &pattern, Scope::Code);
+        let expected: Vec<usize> = (0..0x3E)
+            .map(|i| fixture::IMAGE_BASE + 0x1000 + i)
+            .collect();
+        assert_eq!(matches, expected);
+    }
+
+    #[test]
+    fn matches_at_section_edges() {
+        let mut code = vec![0x90; 0x20];
+        code[..3].copy_from_slice(&[0x8B, 0x45, 0x74]);
+        code[0x1D..].copy_from_slice(&[0x8B, 0x00, 0x74]);
+        let module = module(code, vec![0x8B, 0x00]);
+        let pattern = Pattern::parse("8B ?? 74").unwrap();
+
+        assert_eq!(
+            scan_module_all(&module, &pattern, Scope::Code),
+            [fixture::IMAGE_BASE + 0x1000, fixture::IMAGE_BASE + 0x101D]
+        );
+        // A match may not run off the end of a section
+        assert!(scan_module_all(&module, &pattern, Scope::Data).is_empty());
+    }
+
+    /// A 4 MiB code section, the order of Dunia.dll's
+    fn large_module() -> Module {
+        module(random(4 << 20, 0xDEAD_BEEF).collect(), bytes(0x1000, 7))
+    }
+
+    #[bench]
+    fn bench_multi_scanner(b: &mut test::Bencher) {
+        let module = large_module();
+        let patterns: Vec<Pattern> = PATTERNS
+            .iter()
+            .map(|p| Pattern::parse(p).unwrap())
+            .collect();
+        let mut scanner = MultiScanner::new();
+        for pattern in patterns.iter().filter(|p| p.anchor().is_some()) {
+            scanner.add(pattern, Scope::Code);
         }
+
+        b.iter(|| scanner.scan_module(test::black_box(&module)));
     }
 
-    None
+    #[bench]
+    fn bench_pattern_per_pass(b: &mut test::Bencher) {
+        let module = large_module();
+        let patterns: Vec<Pattern> = PATTERNS
+            .iter()
+            .map(|p| Pattern::parse(p).unwrap())
+            .collect();
+        let patterns: Vec<&Pattern> = patterns.iter().filter(|p| p.anchor().is_some()).collect();
+
+        b.iter(|| {
+            patterns
+                .iter()
+                .map(|pattern| naive(test::black_box(&module), pattern, Scope::Code))
+                .collect::<Vec<_>>()
+        });
+    }
 }
@@ -0,0 +1,180 @@
+//! Virtual method hooks
+//!
+//! Replacing a vtable slot redirects one virtual method without touching any
+//! code, so there is nothing to relocate and nothing for another thread to
+//! execute half-written. Two scopes are supported:
+//!
+//! - [`hook`] swaps the slot in the class's vtable, affecting every object of
+//!   the class (and of subclasses that don't override the method)
+//! - [`hook_instance`] gives one object a private copy of its vtable, a
+//!   shadow vtable, and swaps the slot there, leaving other objects alone
+//!
+//! Both work on any MSVC-layout object: game classes, with the vtable found by
+//! [`rtti::vtable`](crate::patches::rtti::vtable), and our own `cppvtable`
+//! Gear classes. Slot swaps and vtable pointer swaps are [`Patch`]es, so they
+//! are reverted with the rest on unload.
+
+use crate::patches::PatchWrite;
+use crate::patches::memory::{self, Patch, PatchError, Threads};
+use std::ffi::c_void;
+use std::sync::Mutex;
+
+/// Reasons a virtual method could not be hooked
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VTableError {
+    /// The requested slot is past the end of the vtable
+    SlotOutOfRange { index: usize, count: usize },
+    /// The slot or vtable pointer could not be written
+    Patch(PatchError),
+}
+
+impl std::fmt::Display for VTableError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VTableError::SlotOutOfRange { index, count } => {
+                write!(f, "slot {} out of range ({} slots)", index, count)
+            }
+            VTableError::Patch(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// A private copy of an object's vtable
+struct Shadow {
+    object: usize,
+    /// The slot before the vtable followed by the vtable itself
+    slots: Box<[usize]>,
+}
+
+impl Shadow {
+    /// Address the object's vtable pointer is set to
+    fn vtable(&self) -> usize {
+        self.slots[1..].as_ptr() as usize
+    }
+}
+
+/// Shadow vtables of every hooked object
+static SHADOWS: Mutex<Vec<Shadow>> = Mutex::new(Vec::new());
+
+/// Number of slots in a `cppvtable` vtable struct such as `IGearGraphicsVTable`
+#[allow(dead_code)]
+pub const fn slot_count<V>() -> usize {
+    size_of::<V>() / size_of::<usize>()
+}
+
+/// Replace slot `index` of the vtable at `vtable` with `detour`, returning the original
+///
+/// # Safety
+/// `vtable` must point to a vtable with more than `index` slots and `F` must
+/// be the method's function pointer type, `extern "thiscall"` for game
+/// classes.
+#[allow(dead_code)]
+pub unsafe fn hook<F: Copy>(
+    name: &'static str,
+    vtable: usize,
+    index: usize,
+    detour: F,
+) -> Result<F, VTableError> {
+    let slot = vtable + index * size_of::<usize>();
+    let current = unsafe { read(slot) };
+
+    let detour = unsafe { memory::fn_address(detour) };
+    let write = PatchWrite::exact(slot, &current, &detour.to_ne_bytes());
+    let original = unsafe { memory::fn_from_bytes(&current) };
+
+    memory::apply(Patch::new(name, vec![write]).with_threads(Threads::Suspended))
+        .map_err(VTableError::Patch)?;
+
+    #[cfg(debug_assertions)]
+    println!(
+        "patches: Hooked vtable 0x{:08X}[{}] -> 0x{:08X}",
+        vtable, index, detour
+    );
+
+    Ok(original)
+}
+
+/// Replace slot `index` for `object` alone with `detour`, returning the original
+///
+/// The first time one of an object's methods is hooked, its vtable's `slots`
+/// slots are copied into a shadow vtable and its vtable pointer is pointed at
+/// the copy, as the patch `name`; later hooks of the same object only change
+/// the copy and are undone along with that patch. The slot before the vtable
+/// is copied as well, since MSVC keeps the RTTI locator there for
+/// `dynamic_cast`.
+///
+/// For game classes, `slots` is `rtti::slots(module, vtable).len()`; for Gear
+/// classes, [`slot_count`] of the vtable struct.
+///
+/// # Safety
+/// `object` must point to a live object whose vtable has `slots` slots and
+/// `F` must be the method's function pointer type. The object must outlive
+/// the hook: revert it with [`memory::revert`] before the object is freed.
+#[allow(dead_code)]
+pub unsafe fn hook_instance<F: Copy>(
+    name: &'static str,
+    object: *mut c_void,
+    slots: usize,
+    index: usize,
+    detour: F,
+) -> Result<F, VTableError> {
+    if index >= slots {
+        return Err(VTableError::SlotOutOfRange {
+            index,
+            count: slots,
+        });
+    }
+
+    let object = object as usize;
+    let detour = unsafe { memory::fn_address(detour) };
+    let current = unsafe { read(object) };
+    let vtable = usize::from_ne_bytes(current);
+    let mut shadows = SHADOWS.lock().unwrap_or_else(|e| e.into_inner());
+
+    // Already shadowed: only the copy changes
+    if let Some(shadow) = shadows
+        .iter_mut()
+        .find(|shadow| shadow.object == object && shadow.vtable() == vtable)
+    {
+        let count = shadow.slots.len() - 1;
+        if index >= count {
+            return Err(VTableError::SlotOutOfRange { index, count });
+        }
+        let original = shadow.slots[index + 1].to_ne_bytes();
+        shadow.slots[index + 1] = detour;
+        return Ok(unsafe { memory::fn_from_bytes(&original) });
+    }
+
+    let copied =
+        unsafe { std::slice::from_raw_parts((vtable as *const usize).sub(1), slots + 1).to_vec() };
+    let original = copied[index + 1].to_ne_bytes();
+    let mut shadow = Shadow {
+        object,
+        slots: copied.into_boxed_slice(),
+    };
+    shadow.slots[index + 1] = detour;
+
+    let write = PatchWrite::exact(object, &current, &shadow.vtable().to_ne_bytes());
+    memory::apply(Patch::new(name, vec![write])).map_err(VTableError::Patch)?;
+
+    #[cfg(debug_assertions)]
+    println!(
+        "patches: Shadowed vtable of 0x{:08X}, slot {} -> 0x{:08X}",
+        object, index, detour
+    );
+
+    shadows.push(shadow);
+    Ok(unsafe { memory::fn_from_bytes(&original) })
+}
+
+/// Free every shadow vtable
+///
+/// Only safe once the objects' vtable pointers have been restored.
+pub fn release() {
+    SHADOWS.lock().unwrap_or_else(|e| e.into_inner()).clear();
+}
+
+/// The pointer-sized value at `address`
+unsafe fn read(address: usize) -> [u8; size_of::<usize>()] {
+    unsafe { (address as *const usize).read_unaligned() }.to_ne_bytes()
+}
@@ -0,0 +1,756 @@
+//! x86 (32-bit) instruction length decoding and relocation
+//!
+//! Patches and hooks must replace whole instructions: a jump written over the
+//! first half of an instruction leaves its tail to be executed as garbage. The
+//! decoder here knows just enough of the encoding to measure an instruction
+//! (prefixes, opcode, ModRM/SIB, displacement, immediate) and to recognise
+//! relative branches, which are the only position-dependent instructions in
+//! 32-bit code. That is all [`relocate`] needs to move instructions displaced
+//! by a hook into a trampoline.
+//!
+//! Integer, x87 and SSE instructions are covered. VEX/EVEX encodings and
+//! 16-bit relative branches do not occur in Dunia.dll and are rejected.
+
+use crate::patches::memory;
+use crate::patches::pe::Module;
+
+/// Longest valid x86 instruction
+pub const MAX_LEN: usize = 15;
+
+/// Reasons instructions could not be decoded or relocated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The bytes end before the instruction at this address does
+    Truncated(usize),
+    /// The instruction at `address` is invalid or not supported
+    Unsupported { address: usize, opcode: u8 },
+    /// The branch at this address cannot be moved: a loop/jecxz, a branch into
+    /// the bytes being moved, or a target out of rel32 range
+    Unrelocatable(usize),
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::Truncated(address) => {
+                write!(f, "instruction at 0x{:08X} is truncated", address)
+            }
+            DecodeError::Unsupported { address, opcode } => {
+                write!(
+                    f,
+                    "unsupported opcode 0x{:02X} at 0x{:08X}",
+                    opcode, address
+                )
+            }
+            DecodeError::Unrelocatable(address) => {
+                write!(f, "branch at 0x{:08X} cannot be relocated", address)
+            }
+        }
+    }
+}
+
+/// What a relative branch does
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BranchKind {
+    /// `call rel32` (E8)
+    Call,
+    /// `jmp rel8` (EB) or `jmp rel32` (E9)
+    Jump,
+    /// `jcc rel8` (70-7F) or `jcc rel32` (0F 80-8F), with its condition code (0-F)
+    Conditional(u8),
+    /// `loopnz`, `loopz`, `loop` or `jecxz` (E0-E3), which only exist as rel8
+    Loop,
+}
+
+/// A relative branch and where it goes
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Branch {
+    pub kind: BranchKind,
+    /// Offset of the displacement within the instruction
+    pub operand: usize,
+    /// Size of the displacement: 1 or 4
+    pub width: usize,
+    pub target: usize,
+}
+
+/// A decoded instruction
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Instruction {
+    pub address: usize,
+    pub len: usize,
+    /// Number of prefix bytes before the opcode
+    pub prefixes: usize,
+    /// Opcode bytes after the prefixes, most significant first: `0x75`,
+    /// `0x0F85`, `0x0F3800`
+    pub opcode: u32,
+    pub modrm: Option<u8>,
+    pub branch: Option<Branch>,
+}
+
+impl Instruction {
+    /// Address of the next instruction
+    pub fn end(&self) -> usize {
+        self.address + self.len
+    }
+}
+
+/// Immediate operand of an opcode
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Immediate {
+    Nothing,
+    /// imm8
+    Byte,
+    /// imm16
+    Word,
+    /// imm16 or imm32, depending on the operand size
+    Full,
+    /// imm16 + imm8 (`enter`)
+    Enter,
+    /// ptr16:16 or ptr16:32 (far `call`/`jmp`)
+    Far,
+    /// moffs16 or moffs32, depending on the address size
+    Offset,
+    /// rel8
+    Rel8,
+    /// rel32 (rel16 with an operand size prefix, which we reject)
+    Rel32,
+}
+
+/// Decode the instruction at the start of `code`, which is located at `address`
+pub fn decode(code: &[u8], address: usize) -> Result<Instruction, DecodeError> {
+    let byte = |offset: usize| {
+        code.get(offset)
+            .copied()
+            .ok_or(DecodeError::Truncated(address))
+    };
+    let unsupported = |opcode: u8| DecodeError::Unsupported { address, opcode };
+
+    // Prefixes
+    let mut offset = 0;
+    let mut operand_16 = false;
+    let mut address_16 = false;
+    loop {
+        match byte(offset)? {
+            0x66 => operand_16 = true,
+            0x67 => address_16 = true,
+            0xF0 | 0xF2 | 0xF3 | 0x26 | 0x2E | 0x36 | 0x3E | 0x64 | 0x65 => {}
+            _ => break,
+        }
+        offset += 1;
+        if offset >= MAX_LEN {
+            return Err(unsupported(code[0]));
+        }
+    }
+    let prefixes = offset;
+
+    // Opcode
+    let first = byte(offset)?;
+    offset += 1;
+    let (opcode, has_modrm, immediate) = if first == 0x0F {
+        let second = byte(offset)?;
+        offset += 1;
+        match second {
+            0x38 => {
+                let third = byte(offset)?;
+                offset += 1;
+                (0x0F3800 | third as u32, true, Immediate::Nothing)
+            }
+            0x3A => {
+                let third = byte(offset)?;
+                offset += 1;
+                (0x0F3A00 | third as u32, true, Immediate::Byte)
+            }
+            _ => {
+                let (has_modrm, immediate) = two_byte(second).ok_or(unsupported(second))?;
+                (0x0F00 | second as u32, has_modrm, immediate)
+            }
+        }
+    } else {
+        let (has_modrm, immediate) = one_byte(first).ok_or(unsupported(first))?;
+        (first as u32, has_modrm, immediate)
+    };
+
+    // ModRM, SIB and displacement
+    let mut modrm = None;
+    let mut immediate = immediate;
+    if has_modrm {
+        let value = byte(offset)?;
+        offset += 1;
+        modrm = Some(value);
+
+        let mode = value >> 6;
+        let rm = value & 7;
+        let displacement = match (address_16, mode, rm) {
+            (_, 3, _) => 0,
+            (true, 0, 6) => 2,
+            (true, 0, _) => 0,
+            (true, 1, _) => 1,
+            (true, _, _) => 2,
+            (false, _, 4) => {
+                let sib = byte(offset)?;
+                offset += 1;
+                match mode {
+                    0 if sib & 7 == 5 => 4,
+                    0 => 0,
+                    1 => 1,
+                    _ => 4,
+                }
+            }
+            (false, 0, 5) => 4,
+            (false, 0, _) => 0,
+            (false, 1, _) => 1,
+            (false, _, _) => 4,
+        };
+        offset += displacement;
+
+        // test r/m, imm (F6 /0, /1 and F7 /0, /1) is the only group with an immediate
+        let reg = (value >> 3) & 7;
+        immediate = match opcode {
+            0xF6 if reg < 2 => Immediate::Byte,
+            0xF7 if reg < 2 => Immediate::Full,
+            _ => immediate,
+        };
+    }
+
+    // Immediate
+    let full = if operand_16 { 2 } else { 4 };
+    let operand = offset;
+    offset += match immediate {
+        Immediate::Nothing => 0,
+        Immediate::Byte | Immediate::Rel8 => 1,
+        Immediate::Word => 2,
+        Immediate::Full => full,
+        Immediate::Enter => 3,
+        Immediate::Far => full + 2,
+        Immediate::Offset if address_16 => 2,
+        Immediate::Offset => 4,
+        Immediate::Rel32 if operand_16 => return Err(unsupported(first)),
+        Immediate::Rel32 => 4,
+    };
+
+    if offset > MAX_LEN {
+        return Err(unsupported(first));
+    }
+    if offset > code.len() {
+        return Err(DecodeError::Truncated(address));
+    }
+
+    let branch = match immediate {
+        Immediate::Rel8 | Immediate::Rel32 => {
+            let (width, displacement) = if immediate == Immediate::Rel8 {
+                (1, code[operand] as i8 as isize)
+            } else {
+                let rel = &code[operand..operand + 4];
+                (
+                    4,
+                    i32::from_le_bytes([rel[0], rel[1], rel[2], rel[3]]) as isize,
+                )
+            };
+            let kind = match opcode {
+                0xE8 => BranchKind::Call,
+                0xE9 | 0xEB => BranchKind::Jump,
+                0x70..=0x7F => BranchKind::Conditional(first & 0xF),
+                0x0F80..=0x0F8F => BranchKind::Conditional((opcode & 0xF) as u8),
+                _ => BranchKind::Loop,
+            };
+            Some(Branch {
+                kind,
+                operand,
+                width,
+                target: (address + offset).wrapping_add_signed(displacement),
+            })
+        }
+        _ => None,
+    };
+
+    Ok(Instruction {
+        address,
+        len: offset,
+        prefixes,
+        opcode,
+        modrm,
+        branch,
+    })
+}
+
+/// Decode the instruction at `address` in a module
+pub fn decode_at(module: &Module, address: usize) -> Result<Instruction, DecodeError> {
+    let section = module
+        .section_at(address)
+        .ok_or(DecodeError::Truncated(address))?;
+    let start = module.base + section.virtual_address as usize;
+    let code = module
+        .section_data(section)
+        .get(address - start..)
+        .ok_or(DecodeError::Truncated(address))?;
+    decode(&code[..code.len().min(MAX_LEN)], address)
+}
+
+/// Length of the whole instructions at the start of `code` that cover at least `min_len` bytes
+pub fn span(code: &[u8], address: usize, min_len: usize) -> Result<usize, DecodeError> {
+    let mut len = 0;
+    while len < min_len {
+        len += decode(&code[len..], address + len)?.len;
+    }
+    Ok(len)
+}
+
+/// Re-encode the instructions in `code`, located at `from`, to run at `to`
+///
+/// `code` must hold whole instructions. Relative branches are retargeted so
+/// they still reach their original destinations; rel8 jumps are widened to
+/// rel32, so the result may be longer than `code`. Everything else is copied.
+#[allow(dead_code)]
+pub fn relocate(code: &[u8], from: usize, to: usize) -> Result<Vec<u8>, DecodeError> {
+    let mut output = Vec::with_capacity(code.len() * 2);
+    let mut offset = 0;
+
+    while offset < code.len() {
+        let address = from + offset;
+        let instruction = decode(&code[offset..], address)?;
+        let bytes = &code[offset..offset + instruction.len];
+        offset += instruction.len;
+
+        let Some(branch) = instruction.branch else {
+            output.extend_from_slice(bytes);
+            continue;
+        };
+
+        // A branch back into the moved bytes would land in the overwritten code
+        if (from..from + code.len()).contains(&branch.target) {
+            return Err(DecodeError::Unrelocatable(address));
+        }
+
+        // Keep prefixes (branch hints), then the rel32 form of the opcode
+        output.extend_from_slice(&bytes[..instruction.prefixes]);
+        match branch.kind {
+            BranchKind::Call => output.push(0xE8),
+            BranchKind::Jump => output.push(0xE9),
+            BranchKind::Conditional(condition) => {
+                output.extend_from_slice(&[0x0F, 0x80 | condition])
+            }
+            BranchKind::Loop => return Err(DecodeError::Unrelocatable(address)),
+        }
+
+        let next = to + output.len() + 4;
+        let displacement =
+            memory::rel32(next, branch.target).map_err(|_| DecodeError::Unrelocatable(address))?;
+        output.extend_from_slice(&displacement.to_le_bytes());
+    }
+
+    Ok(output)
+}
+
+/// ModRM presence and immediate of one-byte opcodes, None if invalid or a prefix
+fn one_byte(opcode: u8) -> Option<(bool, Immediate)> {
+    use Immediate::*;
+
+    Some(match opcode {
+        // add/or/adc/sbb/and/sub/xor/cmp in their six forms, push/pop seg, daa/das/aaa/aas
+        0x00..=0x3F => match opcode & 7 {
+            _ if matches!(opcode, 0x0F | 0x26 | 0x2E | 0x36 | 0x3E) => return None,
+            0..=3 => (true, Nothing),
+            4 => (false, Byte),
+            5 => (false, Full),
+            _ => (false, Nothing),
+        },
+        // inc/dec/push/pop reg, pusha/popa
+        0x40..=0x61 => (false, Nothing),
+        // bound, arpl
+        0x62 | 0x63 => (true, Nothing),
+        0x64..=0x67 => return None,
+        0x68 => (false, Full),
+        0x69 => (true, Full),
+        0x6A => (false, Byte),
+        0x6B => (true, Byte),
+        // ins/outs
+        0x6C..=0x6F => (false, Nothing),
+        0x70..=0x7F => (false, Rel8),
+        0x80 | 0x82 | 0x83 => (true, Byte),
+        0x81 => (true, Full),
+        // test, xchg, mov, lea, mov seg, pop r/m
+        0x84..=0x8F => (true, Nothing),
+        // nop/xchg, cbw/cwd, wait, pushf/popf, sahf/lahf
+        0x90..=0x99 | 0x9B..=0x9F => (false, Nothing),
+        0x9A => (false, Far),
+        0xA0..=0xA3 => (false, Offset),
+        // movs/cmps/stos/lods/scas
+        0xA4..=0xA7 | 0xAA..=0xAF => (false, Nothing),
+        0xA8 => (false, Byte),
+        0xA9 => (false, Full),
+        0xB0..=0xB7 => (false, Byte),
+        0xB8..=0xBF => (false, Full),
+        0xC0 | 0xC1 | 0xC6 => (true, Byte),
+        0xC7 => (true, Full),
+        0xC2 | 0xCA => (false, Word),
+        // ret, leave, retf, int3, into, iret
+        0xC3 | 0xC9 | 0xCB | 0xCC | 0xCE | 0xCF => (false, Nothing),
+        // les, lds
+        0xC4 | 0xC5 => (true, Nothing),
+        0xC8 => (false, Enter),
+        0xCD => (false, Byte),
+        // shifts by 1 or cl
+        0xD0..=0xD3 => (true, Nothing),
+        0xD4 | 0xD5 => (false, Byte),
+        // salc, xlat
+        0xD6 | 0xD7 => (false, Nothing),
+        // x87
+        0xD8..=0xDF => (true, Nothing),
+        0xE0..=0xE3 => (false, Rel8),
+        0xE4..=0xE7 => (false, Byte),
+        0xE8 | 0xE9 => (false, Rel32),
+        0xEA => (false, Far),
+        0xEB => (false, Rel8),
+        // in/out dx
+        0xEC..=0xEF => (false, Nothing),
+        0xF0 | 0xF2 | 0xF3 => return None,
+        // int1, hlt, cmc, clc/stc/cli/sti/cld/std
+        0xF1 | 0xF4 | 0xF5 | 0xF8..=0xFD => (false, Nothing),
+        // group 3 (immediate decided by ModRM), inc/dec/call/jmp/push r/m
+        0xF6 | 0xF7 | 0xFE | 0xFF => (true, Nothing),
+    })
+}
+
+/// ModRM presence and immediate of two-byte (0F xx) opcodes, None if invalid
+fn two_byte(opcode: u8) -> Option<(bool, Immediate)> {
+    use Immediate::*;
+
+    Some(match opcode {
+        // group 6/7, lar, lsl
+        0x00..=0x03 => (true, Nothing),
+        // syscall, clts, sysret, invd, wbinvd, ud2, femms
+        0x05..=0x09 | 0x0B | 0x0E => (false, Nothing),
+        // prefetch
+        0x0D => (true, Nothing),
+        // 3DNow!, whose opcode is a trailing imm8
+        0x0F => (true, Byte),
+        // SSE moves, prefetch/hint nops, mov cr/dr, SSE conversions
+        0x10..=0x23 | 0x28..=0x2F => (true, Nothing),
+        // wrmsr, rdtsc, rdmsr, rdpmc, sysenter, sysexit, getsec
+        0x30..=0x35 | 0x37 => (false, Nothing),
+        // cmov, SSE/MMX arithmetic
+        0x40..=0x6F => (true, Nothing),
+        // pshuf*, shifts by immediate
+        0x70..=0x73 => (true, Byte),
+        0x74..=0x76 => (true, Nothing),
+        // emms
+        0x77 => (false, Nothing),
+        0x78 | 0x79 | 0x7C..=0x7F => (true, Nothing),
+        0x80..=0x8F => (false, Rel32),
+        // setcc
+        0x90..=0x9F => (true, Nothing),
+        // push/pop fs, cpuid, push/pop gs, rsm
+        0xA0..=0xA2 | 0xA8..=0xAA => (false, Nothing),
+        // shld/shrd by immediate
+        0xA4 | 0xAC => (true, Byte),
+        // bt, shld/shrd by cl, bts, group 15, imul
+        0xA3 | 0xA5 | 0xAB | 0xAD..=0xAF => (true, Nothing),
+        // cmpxchg, lss, btr, lfs, lgs, movzx, popcnt, ud1, btc, bsf, bsr, movsx
+        0xB0..=0xB9 | 0xBB..=0xBF => (true, Nothing),
+        // group 8 (bt* by immediate)
+        0xBA => (true, Byte),
+        // xadd, cmpxchg8b
+        0xC0 | 0xC1 | 0xC3 | 0xC7 => (true, Nothing),
+        // cmpps, pinsrw, pextrw, shufps
+        0xC2 | 0xC4..=0xC6 => (true, Byte),
+        // bswap
+        0xC8..=0xCF => (false, Nothing),
+        // SSE/MMX arithmetic, ud0
+        0xD0..=0xFF => (true, Nothing),
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::patches::fixture::{self, CODE, IMAGE_BASE, SECTION_TABLE};
+
+    const AT: usize = 0x1000_1000;
+
+    fn len(code: &[u8]) -> usize {
+        let instruction = decode(code, AT).unwrap();
+        assert!(instruction.len <= code.len());
+        instruction.len
+    }
+
+    fn rel32(next: usize, target: usize) -> [u8; 4] {
+        (target.wrapping_sub(next) as u32).to_le_bytes()
+    }
+
+    #[test]
+    fn one_byte_opcodes() {
+        assert_eq!(len(&[0x55]), 1);
+        assert_eq!(len(&[0x8B, 0xEC]), 2);
+        assert_eq!(len(&[0x83, 0xEC, 0x10]), 3);
+        assert_eq!(len(&[0x81, 0xEC, 0x60, 0x01, 0x00, 0x00]), 6);
+        assert_eq!(len(&[0x68, 0x00, 0x20, 0x40, 0x00]), 5);
+        assert_eq!(len(&[0xB0, 0x01]), 2);
+        assert_eq!(len(&[0xC2, 0x08, 0x00]), 3);
+        assert_eq!(len(&[0xC8, 0x10, 0x00, 0x00]), 4);
+        assert_eq!(len(&[0x9A, 1, 2, 3, 4, 5, 6]), 7);
+        assert_eq!(len(&[0xD9, 0x45, 0x08]), 3);
+        assert_eq!(len(&[0xCD, 0x2E]), 2);
+    }
+
+    #[test]
+    fn prefixes() {
+        // Operand size shrinks full immediates and far pointers
+        assert_eq!(len(&[0x66, 0xB8, 0x34, 0x12]), 4);
+        assert_eq!(len(&[0x66, 0x81, 0xC1, 0x34, 0x12]), 5);
+        assert_eq!(len(&[0x66, 0x9A, 1, 2, 3, 4]), 6);
+        // ... but not byte immediates
+        assert_eq!(len(&[0x66, 0x83, 0xC1, 0x01]), 4);
+        // Segment override with a moffs32, and address size with a moffs16
+        assert_eq!(len(&[0x64, 0xA1, 0x00, 0x00, 0x00, 0x00]), 6);
+        assert_eq!(len(&[0x67, 0xA1, 0x00, 0x00]), 4);
+
+        let rep_movsd = decode(&[0xF3, 0xA5], AT).unwrap();
+        assert_eq!(
+            (rep_movsd.len, rep_movsd.prefixes, rep_movsd.opcode),
+            (2, 1, 0xA5)
+        );
+        let lock_cmpxchg = decode(&[0xF0, 0x0F, 0xB1, 0x0A], AT).unwrap();
+        assert_eq!(
+            (lock_cmpxchg.len, lock_cmpxchg.prefixes, lock_cmpxchg.opcode),
+            (4, 1, 0x0FB1)
+        );
+    }
+
+    #[test]
+    fn too_many_prefixes() {
+        assert_eq!(
+            decode(&[0x66; 16], AT),
+            Err(DecodeError::Unsupported {
+                address: AT,
+                opcode: 0x66
+            })
+        );
+    }
+
+    #[test]
+    fn modrm_and_sib() {
+        // Register, [reg], [reg+disp8], [reg+disp32], [disp32]
+        assert_eq!(len(&[0x8B, 0xC1]), 2);
+        assert_eq!(len(&[0x8B, 0x01]), 2);
+        assert_eq!(len(&[0x8B, 0x45, 0x08]), 3);
+        assert_eq!(len(&[0x8B, 0x85, 0x00, 0x01, 0x00, 0x00]), 6);
+        assert_eq!(len(&[0x8B, 0x05, 0x00, 0x20, 0x40, 0x00]), 6);
+        // SIB: [esp], [esp+disp8], [esp+disp32], [disp32+index*scale]
+        assert_eq!(len(&[0x8B, 0x04, 0x24]), 3);
+        assert_eq!(len(&[0x8B, 0x44, 0x24, 0x08]), 4);
+        assert_eq!(len(&[0x8B, 0x84, 0x24, 0x00, 0x01, 0x00, 0x00]), 7);
+        assert_eq!(len(&[0x8B, 0x04, 0x85, 0x00, 0x20, 0x40, 0x00]), 7);
+        // ModRM, SIB, disp8 and imm32 together
+        assert_eq!(len(&[0xC7, 0x44, 0x24, 0x08, 0x01, 0x00, 0x00, 0x00]), 8);
+
+        let instruction = decode(&[0x8B, 0x44, 0x24, 0x08], AT).unwrap();
+        assert_eq!(instruction.modrm, Some(0x44));
+        assert_eq!(decode(&[0x55], AT).unwrap().modrm, None);
+    }
+
+    #[test]
+    fn sixteen_bit_addressing() {
+        // [disp16], [bp+disp8], [si] with no SIB byte, [bx+si+disp16]
+        assert_eq!(len(&[0x67, 0x8B, 0x06, 0x34, 0x12]), 5);
+        assert_eq!(len(&[0x67, 0x8B, 0x46, 0x08]), 4);
+        assert_eq!(len(&[0x67, 0x8B, 0x04]), 3);
+        assert_eq!(len(&[0x67, 0x8B, 0x80, 0x34, 0x12]), 5);
+    }
+
+    #[test]
+    fn group_3_immediates() {
+        // test r/m, imm (/0 and /1) has an immediate, not/neg/mul/div do not
+        assert_eq!(len(&[0xF6, 0xC1, 0x01]), 3);
+        assert_eq!(len(&[0xF6, 0xC9, 0x01]), 3);
+        assert_eq!(len(&[0xF6, 0xD1]), 2);
+        assert_eq!(len(&[0xF6, 0x45, 0x08, 0x01]), 4);
+        assert_eq!(len(&[0xF7, 0xC1, 0x00, 0x00, 0x01, 0x00]), 6);
+        assert_eq!(len(&[0x66, 0xF7, 0xC1, 0x34, 0x12]), 5);
+        assert_eq!(len(&[0xF7, 0x45, 0x08, 0x00, 0x00, 0x01, 0x00]), 7);
+        assert_eq!(len(&[0xF7, 0xD8]), 2);
+        assert_eq!(len(&[0xF7, 0x75, 0x08]), 3);
+    }
+
+    #[test]
+    fn two_and_three_byte_opcodes() {
+        assert_eq!(len(&[0x0F, 0xB6, 0x45, 0x08]), 4);
+        assert_eq!(len(&[0x0F, 0x1F, 0x44, 0x00, 0x00]), 5);
+        assert_eq!(len(&[0x0F, 0x0B]), 2);
+        assert_eq!(len(&[0x0F, 0xBA, 0xE0, 0x05]), 4);
+        assert_eq!(len(&[0x0F, 0xC8]), 2);
+        assert_eq!(len(&[0x66, 0x0F, 0x70, 0xC1, 0x1B]), 5);
+
+        // 0F 38 has no immediate, 0F 3A always has an imm8
+        let pshufb = decode(&[0x66, 0x0F, 0x38, 0x00, 0xC1], AT).unwrap();
+        assert_eq!((pshufb.len, pshufb.opcode), (5, 0x0F3800));
+        let palignr = decode(&[0x0F, 0x3A, 0x0F, 0xC1, 0x08], AT).unwrap();
+        assert_eq!((palignr.len, palignr.opcode), (5, 0x0F3A0F));
+        assert_eq!(len(&[0x66, 0x0F, 0x3A, 0x16, 0x44, 0x24, 0x04, 0x01]), 8);
+
+        assert_eq!(
+            decode(&[0x0F, 0x04], AT),
+            Err(DecodeError::Unsupported {
+                address: AT,
+                opcode: 0x04
+            })
+        );
+    }
+
+    #[test]
+    fn truncated_instructions() {
+        for code in [
+            &[][..],
+            &[0x66],
+            &[0x8B],
+            &[0x8B, 0x45],
+            &[0x8B, 0x04],
+            &[0xE8, 0x00, 0x00],
+            &[0x0F],
+            &[0x0F, 0x38],
+            &[0x0F, 0x3A, 0x0F, 0xC1],
+        ] {
+            assert_eq!(
+                decode(code, AT),
+                Err(DecodeError::Truncated(AT)),
+                "{:02X?}",
+                code
+            );
+        }
+    }
+
+    #[test]
+    fn relative_branches() {
+        let jz = decode(&[0x74, 0xFE], AT).unwrap();
+        assert_eq!(
+            jz.branch,
+            Some(Branch {
+                kind: BranchKind::Conditional(4),
+                operand: 1,
+                width: 1,
+                target: AT
+            })
+        );
+
+        let call = decode(&[0xE8, 0x10, 0x00, 0x00, 0x00], AT).unwrap();
+        assert_eq!(call.branch.unwrap().kind, BranchKind::Call);
+        assert_eq!(call.branch.unwrap().target, AT + 0x15);
+
+        let jnz = decode(&[0x0F, 0x85, 0xFA, 0xFF, 0xFF, 0xFF], AT).unwrap();
+        assert_eq!(jnz.branch.unwrap().kind, BranchKind::Conditional(5));
+        assert_eq!(
+            (jnz.branch.unwrap().operand, jnz.branch.unwrap().target),
+            (2, AT)
+        );
+
+        let hinted = decode(&[0x3E, 0x75, 0x00], AT).unwrap();
+        assert_eq!(hinted.branch.unwrap().operand, 2);
+        assert_eq!(
+            decode(&[0xE3, 0x00], AT).unwrap().branch.unwrap().kind,
+            BranchKind::Loop
+        );
+        assert_eq!(decode(&[0xFF, 0x15, 0, 0, 0, 0], AT).unwrap().branch, None);
+
+        // rel16 is never emitted by MSVC
+        assert_eq!(
+            decode(&[0x66, 0xE9, 0x00, 0x00], AT),
+            Err(DecodeError::Unsupported {
+                address: AT,
+                opcode: 0xE9
+            })
+        );
+    }
+
+    #[test]
+    fn span_covers_whole_instructions() {
+        let prologue = [0x55, 0x8B, 0xEC, 0x83, 0xEC, 0x10, 0x53];
+
+        assert_eq!(span(&prologue, AT, 5), Ok(6));
+        assert_eq!(span(&prologue, AT, 6), Ok(6));
+        assert_eq!(span(&prologue, AT, 7), Ok(7));
+        assert_eq!(
+            span(&prologue[..4], AT, 5),
+            Err(DecodeError::Truncated(AT + 3))
+        );
+    }
+
+    #[test]
+    fn relocate_copies_position_independent_code() {
+        let code = [0x55, 0x8B, 0xEC, 0x8B, 0x45, 0x08];
+        assert_eq!(relocate(&code, AT, 0x2000_0000), Ok(code.to_vec()));
+    }
+
+    #[test]
+    fn relocate_widens_rel8() {
+        let to = AT + 0x0100_0000;
+
+        // jz +0x10 becomes jz rel32 to the same target
+        let target = AT + 0x12;
+        let mut expected = vec![0x0F, 0x84];
+        expected.extend_from_slice(&rel32(to + 6, target));
+        assert_eq!(relocate(&[0x74, 0x10], AT, to), Ok(expected.clone()));
+        let moved = decode(&expected, to).unwrap();
+        assert_eq!(moved.branch.unwrap().target, target);
+
+        // jmp rel8 becomes E9, and the prefix of a hinted branch is kept
+        let mut expected = vec![0x90, 0xE9];
+        expected.extend_from_slice(&rel32(to + 6, AT + 0x13));
+        expected.extend_from_slice(&[0x3E, 0x0F, 0x85]);
+        expected.extend_from_slice(&rel32(to + 13, AT + 0x26));
+        assert_eq!(
+            relocate(&[0x90, 0xEB, 0x10, 0x3E, 0x75, 0x20], AT, to),
+            Ok(expected)
+        );
+    }
+
+    #[test]
+    fn relocate_retargets_rel32() {
+        let to = AT - 0x0080_0000;
+        let code = [0x53, 0xE8, 0x00, 0x01, 0x00, 0x00];
+        let target = AT + 6 + 0x100;
+
+        let mut expected = vec![0x53, 0xE8];
+        expected.extend_from_slice(&rel32(to + 6, target));
+        assert_eq!(relocate(&code, AT, to), Ok(expected));
+    }
+
+    #[test]
+    fn relocate_rejects_unmovable_branches() {
+        // Back into the bytes being moved
+        assert_eq!(
+            relocate(&[0x90, 0xEB, 0xFD], AT, 0x2000_0000),
+            Err(DecodeError::Unrelocatable(AT + 1))
+        );
+        // loop has no rel32 form
+        assert_eq!(
+            relocate(&[0xE2, 0x10], AT, 0x2000_0000),
+            Err(DecodeError::Unrelocatable(AT))
+        );
+        // A target that is in range is fine even far away
+        assert!(relocate(&[0xEB, 0x10], AT, AT + 0x7000_0000).is_ok());
+    }
+
+    #[test]
+    fn decode_at_stays_inside_the_image() {
+        let mut code = vec![0xCC; 0x10];
+        code[0x0C..].copy_from_slice(&[0x8B, 0x45, 0x08, 0xC3]);
+        let mut file = fixture::file(&[(".text", 0x1000, code, CODE)], &[]);
+        // .text claims 0x5000 bytes in a 0x2000-byte image
+        fixture::put_u32(&mut file, SECTION_TABLE + 8, 0x5000);
+        let module = Module::from_file(&file).unwrap();
+        let text = IMAGE_BASE + 0x1000;
+
+        assert_eq!(decode_at(&module, text + 0x0C).map(|i| i.len), Ok(3));
+        assert_eq!(decode_at(&module, text + 0x0F).map(|i| i.len), Ok(1));
+        assert_eq!(
+            decode_at(&module, text + 0x2000),
+            Err(DecodeError::Truncated(text + 0x2000))
+        );
+        assert_eq!(
+            decode_at(&module, IMAGE_BASE),
+            Err(DecodeError::Truncated(IMAGE_BASE))
+        );
+    }
+}
@@ -0,0 +1,252 @@
+//! String-reference based function lookup
+//!
+//! Dunia's functions are often easiest to identify by the string literals they
+//! use ("Mesh_Highlight", "ConsoleDeveloperOnly", ...). Those strings survive
+//! recompiles far better than prologue bytes, so a patch can be anchored on
+//! "the function that pushes this string" instead of on the code itself.
+
+use crate::patches::pattern::{Pattern, hex};
+use crate::patches::pe::Module;
+use crate::patches::sigscan::{MultiScanner, Scope, scan_module_all};
+
+/// How far back from a reference we look for the start of its function
+const MAX_FUNCTION_SIZE: usize = 0x4000;
+
+/// Reasons a string could not be turned into function addresses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum XrefError {
+    /// The string does not occur in the module's data sections
+    StringNotFound,
+    /// The string occurs more than once as a standalone literal
+    StringAmbiguous(usize),
+    /// No code references the string's address
+    NoReferences,
+}
+
+impl std::fmt::Display for XrefError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            XrefError::StringNotFound => write!(f, "string not found"),
+            XrefError::StringAmbiguous(count) => {
+                write!(f, "string is ambiguous ({} copies)", count)
+            }
+            XrefError::NoReferences => write!(f, "string is never referenced"),
+        }
+    }
+}
+
+/// Address of the NUL-terminated string literal `text` in the module's data sections
+///
+/// Only standalone literals count: the match must be preceded by a NUL (or the
+/// start of a section), so "Highlight" does not match inside "Mesh_Highlight".
+pub fn find_string(module: &Module, text: &str) -> Result<usize, XrefError> {
+    let mut bytes = text.as_bytes().to_vec();
+    bytes.push(0);
+    let mask = "x".repeat(bytes.len());
+    let pattern = Pattern::from_code(&bytes, &mask).map_err(|_| XrefError::StringNotFound)?;
+
+    let literals: Vec<usize> = scan_module_all(module, &pattern, Scope::Data)
+        .into_iter()
+        .filter(|&address| match module.read(address - 1, 1) {
+            Some(previous) => previous[0] == 0,
+            None => true,
+        })
+        .collect();
+
+    match literals.as_slice() {
+        [] => Err(XrefError::StringNotFound),
+        [address] => Ok(*address),
+        _ => Err(XrefError::StringAmbiguous(literals.len())),
+    }
+}
+
+/// Instructions in code sections that load `target` as an immediate
+///
+/// Finds `push imm32` (68) and `mov reg, imm32` (B8-BF). Returns the address of
+/// each instruction's opcode byte, in ascending order.
+pub fn find_references(module: &Module, target: usize) -> Vec<usize> {
+    let operand = hex(&(target as u32).to_le_bytes());
+
+    let patterns = [
+        Pattern::parse(&format!("68 {}", operand)),
+        Pattern::parse(&format!("B8&F8 {}", operand)),
+    ];
+
+    let mut scanner = MultiScanner::new();
+    for pattern in patterns.iter().flatten() {
+        scanner.add(pattern, Scope::Code);
+    }
+
+    let mut references: Vec<usize> = scanner.scan_module(module).into_iter().flatten().collect();
+    references.sort_unstable();
+    references
+}
+
+/// Start of the function containing `address`
+///
+/// MSVC aligns functions to 16 bytes and fills the gap between them with `int3`
+/// (CC) padding, so we walk back to the nearest 16-byte boundary that directly
+/// follows a run of at least two CC bytes. A single CC or a `ret` (C3) before a
+/// boundary is just as often the last byte of an immediate or displacement, so
+/// it does not count. Alignment inside functions uses multi-byte `lea` nops
+/// rather than CC, so loop heads are not mistaken for function starts either.
+pub fn function_start(module: &Module, address: usize) -> Option<usize> {
+    let lowest = address.saturating_sub(MAX_FUNCTION_SIZE);
+    let mut candidate = address & !0xF;
+
+    while candidate > lowest {
+        if module.read(candidate - 2, 2)? == [0xCC, 0xCC] {
+            return Some(candidate);
+        }
+        candidate -= 0x10;
+    }
+
+    None
+}
+
+/// Start addresses of every function that references the string literal `text`
+#[allow(dead_code)]
+pub fn functions_referencing(module: &Module, text: &str) -> Result<Vec<usize>, XrefError> {
+    let string = find_string(module, text)?;
+
+    let mut functions: Vec<usize> = find_references(module, string)
+        .into_iter()
+        .filter_map(|reference| function_start(module, reference))
+        .collect();
+    functions.dedup();
+
+    if functions.is_empty() {
+        return Err(XrefError::NoReferences);
+    }
+
+    Ok(functions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::patches::fixture::{self, CODE, IMAGE_BASE, RDATA};
+
+    const TEXT: usize = IMAGE_BASE + 0x1000;
+    const STRINGS: usize = IMAGE_BASE + 0x2000;
+    const MESH_HIGHLIGHT: usize = STRINGS + 1;
+    const HIGHLIGHT: usize = STRINGS + 16;
+
+    /// Three functions at +0x10, +0x20 and +0x40, each separated by CC padding
+    ///
+    /// The second and third have a C3 and a CC operand byte right before a
+    /// 16-byte boundary in their middle.
+    fn module() -> Module {
+        let push = |target: usize| [&[0x68][..], &(target as u32).to_le_bytes()].concat();
+        let functions: [(usize, Vec<u8>); 3] = [
+            // push ebp | mov ebp, esp | push "Highlight" | pop ebp | ret
+            (
+                0x10,
+                [&[0x55, 0x8B, 0xEC][..], &push(HIGHLIGHT), &[0x5D, 0xC3]].concat(),
+            ),
+            // ... | mov eax, 0C3000000h | nop x4 | push "Mesh_Highlight" | ...
+            (
+                0x20,
+                [
+                    &[0x55, 0x8B, 0xEC][..],
+                    &[0x90; 8],
+                    &[0xB8, 0x00, 0x00, 0x00, 0xC3],
+                    &[0x90; 4],
+                    &push(MESH_HIGHLIGHT),
+                    &[0x5D, 0xC3],
+                ]
+                .concat(),
+            ),
+            // ... | add eax, -34h | mov ecx, "Mesh_Highlight" | ...
+            (
+                0x40,
+                [
+                    &[0x55, 0x8B, 0xEC][..],
+                    &[0x90; 10],
+                    &[0x83, 0xC0, 0xCC],
+                    &[0xB9],
+                    &(MESH_HIGHLIGHT as u32).to_le_bytes(),
+                    &[0x5D, 0xC3],
+                ]
+                .concat(),
+            ),
+        ];
+
+        let mut text = vec![0xCC; 0x100];
+        for (offset, code) in &functions {
+            text[*offset..offset + code.len()].copy_from_slice(code);
+        }
+        let strings = b"\0Mesh_Highlight\0Highlight\0Dup\0Dup\0Unused\0".to_vec();
+
+        fixture::module(
+            &[
+                (".text", 0x1000, text, CODE),
+                (".rdata", 0x2000, strings, RDATA),
+            ],
+            &[],
+        )
+    }
+
+    #[test]
+    fn finds_standalone_strings_only() {
+        let module = module();
+
+        assert_eq!(find_string(&module, "Mesh_Highlight"), Ok(MESH_HIGHLIGHT));
+        assert_eq!(find_string(&module, "Highlight"), Ok(HIGHLIGHT));
+        assert_eq!(
+            find_string(&module, "Dup"),
+            Err(XrefError::StringAmbiguous(2))
+        );
+        assert_eq!(
+            find_string(&module, "Light"),
+            Err(XrefError::StringNotFound)
+        );
+        assert_eq!(find_string(&module, "Mesh"), Err(XrefError::StringNotFound));
+    }
+
+    #[test]
+    fn finds_push_and_mov_references() {
+        let module = module();
+
+        assert_eq!(find_references(&module, HIGHLIGHT), [TEXT + 0x13]);
+        assert_eq!(
+            find_references(&module, MESH_HIGHLIGHT),
+            [TEXT + 0x34, TEXT + 0x50]
+        );
+        assert!(find_references(&module, STRINGS + 34).is_empty());
+    }
+
+    #[test]
+    fn function_start_needs_padding() {
+        let module = module();
+
+        assert_eq!(function_start(&module, TEXT + 0x13), Some(TEXT + 0x10));
+        // The C3 before +0x30 and the CC before +0x50 are operand bytes
+        assert_eq!(function_start(&module, TEXT + 0x34), Some(TEXT + 0x20));
+        assert_eq!(function_start(&module, TEXT + 0x50), Some(TEXT + 0x40));
+        // Nothing but the start of the section before the first boundary
+        assert_eq!(function_start(&module, TEXT + 0x05), None);
+    }
+
+    #[test]
+    fn functions_referencing_strings() {
+        let module = module();
+
+        assert_eq!(
+            functions_referencing(&module, "Mesh_Highlight"),
+            Ok(vec![TEXT + 0x20, TEXT + 0x40])
+        );
+        assert_eq!(
+            functions_referencing(&module, "Highlight"),
+            Ok(vec![TEXT + 0x10])
+        );
+        assert_eq!(
+            functions_referencing(&module, "Unused"),
+            Err(XrefError::NoReferences)
+        );
+        assert_eq!(
+            functions_referencing(&module, "Dup"),
+            Err(XrefError::StringAmbiguous(2))
+        );
+    }
+}